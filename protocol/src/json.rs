@@ -39,7 +39,7 @@ pub struct Info {
     pub maxclients: u16,
     pub track: String,
     pub cars: Vec<String>,
-    pub timeofday: u64,
+    pub timeofday: i64, //sun angle
     pub session: u16,
     pub sessiontypes: Vec<u8>,
     pub durations: Vec<i64>,
@@ -54,5 +54,5 @@ pub struct Info {
     pub timed: bool,
     pub extra: bool,
     pub pit: bool,
-    pub inverted: u8,
+    pub inverted: i16,
 }
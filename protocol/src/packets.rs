@@ -102,6 +102,15 @@ macro_rules! packet_enum {
                     )*
                 }
             }
+
+            /// Returns the name of this packet.
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(
+                        $ident::$packet(_) => stringify!($packet),
+                    )*
+                }
+            }
         }
 
         impl crate::Readable for $ident {
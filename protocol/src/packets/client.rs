@@ -181,11 +181,11 @@ impl Readable for Checksum {
 #[derive(Debug, Clone)]
 pub struct EventS {
     //event_length: u16,
-    event_type: CollisionType,
-    other_car: Option<u8>,
-    impact_speed: f32,
-    world_pos: Vec3f,
-    real_pos: Vec3f,
+    pub event_type: CollisionType,
+    pub other_car: Option<u8>,
+    pub impact_speed: f32,
+    pub world_pos: Vec3f,
+    pub real_pos: Vec3f,
 }
 impl Writeable for EventS {
     fn write(&self, buffer: &mut Vec<u8>) -> anyhow::Result<()> {
@@ -0,0 +1,459 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+use flume::Receiver;
+use protocol::packets::server::{Bops, KickReason, SessionType, TestServer};
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::chat::{self, Verdict};
+use crate::server::Server;
+use crate::system::SystemExecutor;
+
+/// Something an admin can do, typed in the chat like `/kick 3` or sent to the HTTP API.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminCommand {
+    Drivers,
+    Standings,
+    Kick {
+        car_id: usize,
+    },
+    /// Kicks the driver and bans their guid until the server restarts, reloads keep it.
+    Ban {
+        car_id: usize,
+    },
+    Chat {
+        car_id: usize,
+        message: String,
+    },
+    /// Stops the driver's messages from reaching the chat, even after reconnecting.
+    Mute {
+        car_id: usize,
+    },
+    Unmute {
+        car_id: usize,
+    },
+    Broadcast {
+        message: String,
+    },
+    NextSession,
+    RestartSession,
+    /// Switches to the weather at `index` in `weathers`.
+    Weather {
+        index: usize,
+    },
+    Ballast {
+        car_id: usize,
+        kg: f32,
+    },
+    Restrictor {
+        car_id: usize,
+        restrictor: f32,
+    },
+}
+
+impl AdminCommand {
+    /// Commands that only look at the server.
+    pub fn is_query(&self) -> bool {
+        matches!(self, AdminCommand::Drivers | AdminCommand::Standings)
+    }
+
+    /// Parses a chat command, the message without its leading `/`.
+    pub fn parse(command: &str) -> anyhow::Result<AdminCommand> {
+        let mut words = command.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let mut arg = |what: &str| {
+            words
+                .next()
+                .with_context(|| format!("/{} needs {}", name, what))
+        };
+        let command = match name {
+            "drivers" => AdminCommand::Drivers,
+            "standings" => AdminCommand::Standings,
+            "kick" => AdminCommand::Kick {
+                car_id: parse(arg("a car id")?)?,
+            },
+            "ban" => AdminCommand::Ban {
+                car_id: parse(arg("a car id")?)?,
+            },
+            "chat" => AdminCommand::Chat {
+                car_id: parse(arg("a car id")?)?,
+                message: rest(command, 2),
+            },
+            "mute" => AdminCommand::Mute {
+                car_id: parse(arg("a car id")?)?,
+            },
+            "unmute" => AdminCommand::Unmute {
+                car_id: parse(arg("a car id")?)?,
+            },
+            "broadcast" => AdminCommand::Broadcast {
+                message: rest(command, 1),
+            },
+            "next_session" => AdminCommand::NextSession,
+            "restart_session" => AdminCommand::RestartSession,
+            "weather" => AdminCommand::Weather {
+                index: parse(arg("a weather index")?)?,
+            },
+            "ballast" => AdminCommand::Ballast {
+                car_id: parse(arg("a car id")?)?,
+                kg: parse(arg("kilograms")?)?,
+            },
+            "restrictor" => AdminCommand::Restrictor {
+                car_id: parse(arg("a car id")?)?,
+                restrictor: parse(arg("a restrictor")?)?,
+            },
+            _ => bail!("unknown command /{}", name),
+        };
+        Ok(command)
+    }
+}
+
+fn parse<T: FromStr>(word: &str) -> anyhow::Result<T> {
+    word.parse()
+        .ok()
+        .with_context(|| format!("invalid value {}", word))
+}
+
+/// Everything after the first `words` words.
+fn rest(command: &str, words: usize) -> String {
+    command
+        .splitn(words + 1, char::is_whitespace)
+        .nth(words)
+        .unwrap_or_default()
+        .trim()
+        .to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DriverInfo {
+    pub car_id: usize,
+    pub name: String,
+    pub guid: String,
+    pub car_model: String,
+    pub car_skin: String,
+    pub ping: u32,
+    pub laps: u32,
+    pub ballast_kg: f32,
+    pub restrictor: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Standing {
+    pub position: usize,
+    pub car_id: usize,
+    pub name: String,
+    pub laps: u32,
+    /// Best lap time in milliseconds.
+    pub best_lap: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum AdminReply {
+    Done,
+    Drivers(Vec<DriverInfo>),
+    Standings(Vec<Standing>),
+}
+
+impl fmt::Display for AdminReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminReply::Done => write!(f, "Done"),
+            AdminReply::Drivers(drivers) => {
+                for d in drivers.iter() {
+                    writeln!(
+                        f,
+                        "{}: {} {} {}ms {} laps",
+                        d.car_id, d.name, d.car_model, d.ping, d.laps
+                    )?;
+                }
+                Ok(())
+            }
+            AdminReply::Standings(standings) => {
+                for s in standings.iter() {
+                    writeln!(f, "{}. {} {} laps", s.position, s.name, s.laps)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A command from outside the server thread, answered on `reply`.
+pub struct AdminRequest {
+    pub command: AdminCommand,
+    pub reply: oneshot::Sender<anyhow::Result<AdminReply>>,
+}
+
+pub fn register(systems: &mut SystemExecutor<Server>, requests: Receiver<AdminRequest>) {
+    systems.add_system("admin", move |server| {
+        for request in requests.try_iter() {
+            log::info!("Admin command {:?}", request.command);
+            let _ = request.reply.send(execute(server, request.command));
+        }
+        Ok(())
+    });
+}
+
+/// Handles a chat message starting with `/` from `car_id`, `/admin <password>`
+/// makes the driver an admin.
+pub fn handle_chat_command(server: &mut Server, car_id: usize, message: &str) {
+    let command = message.trim_start_matches('/');
+    let reply = match command.split_once(' ').unwrap_or((command, "")) {
+        ("admin", password) => login(server, car_id, password.trim()),
+        _ => {
+            let is_admin = server
+                .clients
+                .get_from_car_id(car_id)
+                .is_some_and(|client| client.is_admin());
+            match is_admin {
+                true => AdminCommand::parse(command)
+                    .and_then(|command| {
+                        log::info!("Admin command {:?} from {}", command, car_id);
+                        execute(server, command)
+                    })
+                    .map(|reply| reply.to_string())
+                    .unwrap_or_else(|e| e.to_string()),
+                false => "You are not an admin".into(),
+            }
+        }
+    };
+    server.send_chat(car_id, reply.trim_end());
+}
+
+/// Attempts count against the chat rate limit and failed ones are logged,
+/// so the password cannot be guessed at packet rate or unnoticed.
+fn login(server: &mut Server, car_id: usize, password: &str) -> String {
+    let guid = match server.clients.get_from_car_id(car_id) {
+        Some(client) => client.guid.clone(),
+        None => return String::new(),
+    };
+    let reply = if server
+        .moderation
+        .is_rate_limited(&server.config.chat, &guid)
+    {
+        "You are sending messages too fast"
+    } else {
+        let admin_password = server.options.read().unwrap().admin_password.clone();
+        let correct = admin_password.is_some_and(|admin_password| {
+            constant_time_eq(admin_password.as_bytes(), password.as_bytes())
+        });
+        if correct {
+            if let Some(client) = server.clients.get_from_car_id(car_id) {
+                client.set_admin(true);
+            }
+            return "You are now an admin".into();
+        }
+        "Wrong admin password"
+    };
+    log::warn!("Failed admin login from {} ({}): {}", car_id, guid, reply);
+    // the password itself stays out of the log
+    let name = chat::driver_name(server, car_id);
+    server
+        .moderation
+        .log(&guid, car_id, &name, &Verdict::Drop(None), "/admin ***");
+    reply.into()
+}
+
+/// Compares without stopping at the first differing byte, so the time taken
+/// does not give away how much of a password or token was guessed right.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Runs `command` the same way for the chat and the HTTP API.
+pub fn execute(server: &mut Server, command: AdminCommand) -> anyhow::Result<AdminReply> {
+    match command {
+        AdminCommand::Drivers => return Ok(AdminReply::Drivers(drivers(server))),
+        AdminCommand::Standings => return Ok(AdminReply::Standings(standings(server))),
+        AdminCommand::Kick { car_id } => {
+            connected(server, car_id)?;
+            server.kick(car_id, KickReason::Kick);
+        }
+        AdminCommand::Ban { car_id } => {
+            let guid = connected(server, car_id)?.guid.clone();
+            server.options.write().unwrap().ban(guid);
+            server.kick(car_id, KickReason::KickBan);
+        }
+        AdminCommand::Chat { car_id, message } => {
+            connected(server, car_id)?.send_chat(message);
+        }
+        AdminCommand::Mute { car_id } => {
+            let guid = connected(server, car_id)?.guid.clone();
+            server.moderation.mute(&guid);
+        }
+        AdminCommand::Unmute { car_id } => {
+            let guid = connected(server, car_id)?.guid.clone();
+            if !server.moderation.unmute(&guid) {
+                bail!("car {} is not muted", car_id);
+            }
+        }
+        AdminCommand::Broadcast { message } => server.broadcast_chat(message),
+        AdminCommand::NextSession => server.next_session(),
+        AdminCommand::RestartSession => server.restart_session(),
+        AdminCommand::Weather { index } => {
+            let packet = {
+                let mut options = server.options.write().unwrap();
+                let weather = options
+                    .weathers
+                    .get(index)
+                    .cloned()
+                    .with_context(|| format!("there is no weather {}", index))?;
+                options.current_weather = weather;
+                options.current_weather.update();
+                TestServer::Weather(options.current_weather().into())
+            };
+            server.broadcast_with(|c| c.send_packet(packet.clone()));
+        }
+        AdminCommand::Ballast { car_id, kg } => {
+            // NaN would pass a plain `kg < 0.0` and reach every client
+            if !kg.is_finite() || kg < 0.0 {
+                bail!("ballast must be 0 kg or more");
+            }
+            set_bop(server, car_id, |status| status.ballast_kg = kg)?;
+        }
+        AdminCommand::Restrictor { car_id, restrictor } => {
+            if !(0.0..=400.0).contains(&restrictor) {
+                bail!("restrictor must be between 0 and 400");
+            }
+            set_bop(server, car_id, |status| status.restrictor = restrictor)?;
+        }
+    }
+    Ok(AdminReply::Done)
+}
+
+fn connected(server: &Server, car_id: usize) -> anyhow::Result<&crate::client::Client> {
+    server
+        .clients
+        .get_from_car_id(car_id)
+        .with_context(|| format!("no driver in car {}", car_id))
+}
+
+fn set_bop(
+    server: &Server,
+    car_id: usize,
+    set: impl FnOnce(&mut crate::client::ClientStatus),
+) -> anyhow::Result<()> {
+    let client = connected(server, car_id)?;
+    set(&mut client.status_mut());
+    server.cars.save_progress(car_id, &client.status());
+    let bops: Bops = (&server.clients).into();
+    server.broadcast_with(|c| c.send_packet(TestServer::Bops(bops.clone())));
+    Ok(())
+}
+
+fn drivers(server: &Server) -> Vec<DriverInfo> {
+    let cars = server.cars.lock().unwrap();
+    server
+        .clients
+        .iter()
+        .filter_map(|client| {
+            let car = cars.get(client.car_id)?;
+            let status = client.status();
+            Some(DriverInfo {
+                car_id: client.car_id,
+                name: car.driver.as_ref()?.name.clone(),
+                guid: client.guid.clone(),
+                car_model: car.model.clone(),
+                car_skin: car.skin.clone(),
+                ping: status.ping,
+                laps: status.laps,
+                ballast_kg: status.ballast_kg,
+                restrictor: status.restrictor,
+            })
+        })
+        .collect()
+}
+
+/// Connected drivers ordered by laps and who got there first in a race,
+/// by best lap otherwise.
+pub fn standings(server: &Server) -> Vec<Standing> {
+    let options = server.options.read().unwrap();
+    let laps = options.laps.laps();
+    let is_race = options.sessions.get_current_session().session_type == SessionType::Race;
+    let cars = server.cars.lock().unwrap();
+
+    let mut entries: Vec<_> = server
+        .clients
+        .iter()
+        .map(|client| {
+            let best_lap = laps
+                .iter()
+                .filter(|lap| lap.car_id as usize == client.car_id && lap.laptime > 0)
+                .map(|lap| lap.laptime)
+                .min();
+            let name = cars
+                .get(client.car_id)
+                .and_then(|car| car.driver.as_ref())
+                .map(|driver| driver.name.clone())
+                .unwrap_or_default();
+            let status = client.status();
+            (
+                status.last_lap_timestamp,
+                Standing {
+                    position: 0,
+                    car_id: client.car_id,
+                    name,
+                    laps: status.laps,
+                    best_lap,
+                },
+            )
+        })
+        .collect();
+    if is_race {
+        entries.sort_by(|(a_time, a), (b_time, b)| b.laps.cmp(&a.laps).then(a_time.cmp(b_time)));
+    } else {
+        entries.sort_by_key(|(_, s)| s.best_lap.unwrap_or(u32::MAX));
+    }
+    entries
+        .into_iter()
+        .enumerate()
+        .map(|(i, (_, standing))| Standing {
+            position: i + 1,
+            ..standing
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_passwords_whole() {
+        assert!(constant_time_eq(b"director", b"director"));
+        assert!(!constant_time_eq(b"director", b"directo"));
+        assert!(!constant_time_eq(b"director", b"Director"));
+    }
+
+    #[test]
+    fn parses_chat_commands() {
+        assert_eq!(
+            AdminCommand::parse("kick 3").unwrap(),
+            AdminCommand::Kick { car_id: 3 }
+        );
+        assert_eq!(
+            AdminCommand::parse("chat 2 slow  down").unwrap(),
+            AdminCommand::Chat {
+                car_id: 2,
+                message: "slow  down".into()
+            }
+        );
+        assert_eq!(
+            AdminCommand::parse("ballast 1 25.5").unwrap(),
+            AdminCommand::Ballast {
+                car_id: 1,
+                kg: 25.5
+            }
+        );
+        assert_eq!(
+            AdminCommand::parse("mute 4").unwrap(),
+            AdminCommand::Mute { car_id: 4 }
+        );
+        assert!(AdminCommand::parse("kick").is_err());
+        assert!(AdminCommand::parse("kick me").is_err());
+        assert!(AdminCommand::parse("fly").is_err());
+    }
+}
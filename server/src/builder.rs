@@ -0,0 +1,245 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+
+use anyhow::Context;
+use tokio::runtime;
+use tokio::sync::{broadcast, oneshot};
+use tokio::task::LocalSet;
+
+use crate::car::Cars;
+use crate::config::Config;
+use crate::event::{EventBus, ServerEvent};
+use crate::http::HttpServer;
+use crate::live::LiveFeed;
+use crate::metrics::Metrics;
+use crate::option::ServerOptions;
+use crate::server::{self, Server};
+use crate::system::{SysResult, SystemExecutor};
+use crate::tickloop::TickLoop;
+use crate::udpserver::UdpServer;
+use crate::{admin, chat, live, lobby, metrics, penalty, plugin, reload, session, vote, weather};
+
+type SendSystem = Box<dyn FnMut(&mut Server) -> SysResult + Send>;
+
+/// Addresses the server actually listens on, useful when the config asks for port 0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundAddresses {
+    pub tcp: SocketAddr,
+    pub udp: SocketAddr,
+    pub http: SocketAddr,
+}
+
+/// State shared between the server thread and its handle.
+struct Shared {
+    addresses: BoundAddresses,
+    /// The config last applied, at start or by a reload.
+    config: Mutex<Arc<Config>>,
+    options: Arc<RwLock<ServerOptions>>,
+    cars: Arc<Cars>,
+}
+
+/// Starts a server from a `Config`.
+///
+/// Every server runs on its own thread, so any number of them can
+/// be started in the same process.
+pub struct ServerBuilder {
+    config: Config,
+    systems: Vec<(String, SendSystem)>,
+}
+
+impl ServerBuilder {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            systems: Vec::new(),
+        }
+    }
+
+    /// Adds a system that runs every tick after the built-in ones.
+    pub fn system(
+        mut self,
+        name: impl Into<String>,
+        system: impl FnMut(&mut Server) -> SysResult + Send + 'static,
+    ) -> Self {
+        self.systems.push((name.into(), Box::new(system)));
+        self
+    }
+
+    /// Binds every socket and starts the server.
+    pub async fn start(self) -> anyhow::Result<ServerHandle> {
+        self.config.validate()?;
+
+        let events = EventBus::new();
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let (finished_tx, finished_rx) = oneshot::channel();
+
+        let thread_events = events.clone();
+        thread::Builder::new()
+            .name(format!("server {}", self.config.server.name))
+            .spawn(move || {
+                let result = self.run(thread_events, ready_tx, async {
+                    // dropping the handle stops the server as well
+                    let _ = shutdown_rx.await;
+                });
+                let _ = finished_tx.send(result);
+            })
+            .context("failed to spawn server thread")?;
+
+        let shared = ready_rx
+            .await
+            .context("server stopped before it was ready")??;
+        Ok(ServerHandle {
+            shared,
+            events,
+            shutdown: Some(shutdown_tx),
+            finished: Some(finished_rx),
+        })
+    }
+
+    fn run(
+        self,
+        events: EventBus,
+        ready: oneshot::Sender<anyhow::Result<Shared>>,
+        shutdown: impl std::future::Future<Output = ()>,
+    ) -> anyhow::Result<()> {
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        // clients keep their state in cells, so the server runs on a single thread
+        let local = LocalSet::new();
+        local.block_on(&runtime, async move {
+            let (server, tick_loop, systems, shared) = match self.bind(events).await {
+                Ok(bound) => bound,
+                Err(e) => {
+                    let _ = ready.send(Err(e));
+                    return;
+                }
+            };
+            let _ = ready.send(Ok(shared));
+            server.run(tick_loop, systems, shutdown).await;
+        });
+        Ok(())
+    }
+
+    async fn bind(
+        self,
+        events: EventBus,
+    ) -> anyhow::Result<(Server, TickLoop, SystemExecutor<Server>, Shared)> {
+        let config = Arc::new(self.config);
+        let cars = Arc::new(Cars::new(Arc::clone(&config)));
+        let options = ServerOptions::new(Arc::clone(&config));
+        options.write().unwrap().update_weather();
+        let metrics = Arc::new(Metrics::default());
+        let udp_server = UdpServer::bind(Arc::clone(&config), Arc::clone(&metrics)).await?;
+
+        let server = Server::bind(
+            config.clone(),
+            cars.clone(),
+            options.clone(),
+            udp_server.received_packets(),
+            udp_server.packets_to_send(),
+            events,
+            Arc::clone(&metrics),
+        )
+        .await?;
+
+        let (admin_requests, admin_rx) = flume::unbounded();
+        let live = LiveFeed::new();
+        let http = HttpServer::serve(
+            config.clone(),
+            options.clone(),
+            cars.clone(),
+            server.start_time,
+            admin_requests,
+            live.clone(),
+            metrics,
+        )
+        .await?;
+        lobby::start(
+            config.clone(),
+            options.clone(),
+            cars.clone(),
+            server.start_time,
+        );
+
+        let tick_loop = TickLoop::new(config.server.client_send_interval_hz.into());
+        let mut systems = SystemExecutor::new();
+        session::register(&mut systems);
+        weather::register(&mut systems);
+        vote::register(&mut systems);
+        penalty::register(&mut systems);
+        plugin::register(&mut systems);
+        admin::register(&mut systems, admin_rx);
+        chat::register(&mut systems);
+        live::register(&mut systems, live, server.events().subscribe());
+        metrics::register(&mut systems);
+        server::register(&mut systems);
+        systems.on_panic(|server| server.clear_poison());
+        for (name, system) in self.systems {
+            systems.add_system(name, system);
+        }
+
+        let shared = Shared {
+            addresses: BoundAddresses {
+                tcp: server.tcp_addr(),
+                udp: udp_server.local_addr(),
+                http,
+            },
+            config: Mutex::new(config),
+            options,
+            cars,
+        };
+        Ok((server, tick_loop, systems, shared))
+    }
+}
+
+/// A running server. Dropping the handle shuts the server down.
+pub struct ServerHandle {
+    shared: Shared,
+    events: EventBus,
+    shutdown: Option<oneshot::Sender<()>>,
+    finished: Option<oneshot::Receiver<anyhow::Result<()>>>,
+}
+
+impl ServerHandle {
+    pub fn addresses(&self) -> BoundAddresses {
+        self.shared.addresses
+    }
+
+    /// Applies the parts of `config` that can change while the server is running,
+    /// see `reload::reload`. An invalid config leaves the server untouched.
+    pub fn reload(&self, config: Config) -> anyhow::Result<()> {
+        let mut current = self.shared.config.lock().unwrap();
+        reload::reload(&current, &config, &self.shared.options, &self.shared.cars)?;
+        // the next reload only warns about what changed since this one
+        *current = Arc::new(config);
+        Ok(())
+    }
+
+    /// Subscribes to events published from now on.
+    pub fn events(&self) -> broadcast::Receiver<ServerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Waits until the server has stopped and released its sockets.
+    /// Cancelling the wait, e.g. in `select!`, is fine.
+    pub async fn wait(&mut self) -> anyhow::Result<()> {
+        let finished = match &mut self.finished {
+            Some(finished) => finished,
+            None => return Ok(()),
+        };
+        let result = finished.await;
+        self.finished = None;
+        result.context("server thread panicked")?
+    }
+
+    /// Disconnects every client and stops the server.
+    pub async fn shutdown(mut self) -> anyhow::Result<()> {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        self.wait().await
+    }
+}
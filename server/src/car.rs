@@ -1,20 +1,40 @@
-use crate::config::Config;
+use crate::{
+    client::ClientStatus,
+    config::{Car as ConfigCar, Config},
+};
 use protocol::{
     json::Car as JsonCar,
     packets::server::{Car as PacketCar, CarList},
 };
-use std::sync::{Arc, Mutex};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 #[derive(Debug)]
 pub struct NoSlotsForCar;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BookingError {
+    /// The server has no car of the requested model.
+    IllegalCar,
+    ServerFull,
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Driver {
     pub name: String,
     pub team: String,
     pub nation: String,
     pub guid: String,
 }
+/// Slot kept for a driver that lost connection so they can reconnect to the same car.
+#[derive(Debug, Clone)]
+pub struct Reservation {
+    pub guid: String,
+    pub since: Instant,
+}
+
 #[derive(Debug, Clone)]
 pub struct Car {
     pub driver: Option<Driver>,
@@ -26,6 +46,101 @@ pub struct Car {
     pub damage2: f32,
     pub damage3: f32,
     pub damage4: f32,
+    pub laps: u32,
+    pub tyre_compound: String,
+    pub mandatory_pit: bool,
+    pub ballast_kg: f32,
+    pub restrictor: f32,
+    /// Meters driven and contacts made, kept so reconnecting does not reset contacts per km.
+    pub distance_driven: f32,
+    pub contacts: u32,
+    pub reservation: Option<Reservation>,
+    /// Guid of the driver the entry list reserves the car for.
+    pub entry_guid: Option<String>,
+    /// Driver that booked the car from the launcher during the booking session.
+    pub booking: Option<Driver>,
+}
+
+impl Car {
+    /// Returns true if the entry list, a booking or the connected driver puts `guid` in this car.
+    fn is_booked_for(&self, guid: &str) -> bool {
+        self.entry_guid.as_deref() == Some(guid)
+            || self
+                .booking
+                .as_ref()
+                .is_some_and(|booking| booking.guid == guid)
+            || self
+                .driver
+                .as_ref()
+                .is_some_and(|driver| driver.guid == guid)
+    }
+
+    /// Returns true if the car belongs to `guid`, either because the driver is
+    /// still connected, booked it or their reservation has not expired yet.
+    fn is_held_by(&self, guid: &str, grace_period: Duration) -> bool {
+        if let Some(driver) = &self.driver {
+            return driver.guid == guid;
+        }
+        if let Some(booking) = &self.booking {
+            return booking.guid == guid;
+        }
+        match &self.reservation {
+            Some(reservation) => {
+                reservation.guid == guid && reservation.since.elapsed() < grace_period
+            }
+            None => false,
+        }
+    }
+
+    fn is_free(&self, guid: &str, grace_period: Duration) -> bool {
+        if self.driver.is_some() {
+            return false;
+        }
+        if let Some(entry_guid) = &self.entry_guid {
+            if entry_guid != guid {
+                return false;
+            }
+        }
+        if let Some(booking) = &self.booking {
+            if booking.guid != guid {
+                return false;
+            }
+        }
+        match &self.reservation {
+            Some(reservation) => reservation.since.elapsed() >= grace_period,
+            None => true,
+        }
+    }
+
+    fn reset_progress(&mut self) {
+        self.damage = 0.0;
+        self.damage1 = 0.0;
+        self.damage2 = 0.0;
+        self.damage3 = 0.0;
+        self.damage4 = 0.0;
+        self.laps = 0;
+        self.tyre_compound = String::default();
+        self.mandatory_pit = false;
+        self.ballast_kg = 0.0;
+        self.restrictor = 0.0;
+        self.distance_driven = 0.0;
+        self.contacts = 0;
+    }
+
+    pub fn save_progress(&mut self, status: &ClientStatus) {
+        self.damage = status.damage_zone_level[0];
+        self.damage1 = status.damage_zone_level[1];
+        self.damage2 = status.damage_zone_level[2];
+        self.damage3 = status.damage_zone_level[3];
+        self.damage4 = status.damage_zone_level[4];
+        self.laps = status.laps;
+        self.tyre_compound = status.current_tyre_compound.clone();
+        self.mandatory_pit = status.mandatory_pit;
+        self.ballast_kg = status.ballast_kg;
+        self.restrictor = status.restrictor;
+        self.distance_driven = status.distance_driven;
+        self.contacts = status.contacts;
+    }
 }
 impl From<&Cars> for Vec<PacketCar> {
     fn from(cars: &Cars) -> Vec<PacketCar> {
@@ -70,13 +185,16 @@ impl From<&Cars> for Vec<PacketCar> {
     }
 }
 
-pub struct Cars(Mutex<Vec<Car>>);
+pub struct Cars {
+    cars: Mutex<Vec<Car>>,
+    reconnect_grace_period: Duration,
+}
 
 impl std::ops::Deref for Cars {
     type Target = Mutex<Vec<Car>>;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.cars
     }
 }
 
@@ -93,7 +211,7 @@ impl From<Car> for JsonCar {
         };
 
         let is_connected = c.driver.is_some();
-        let driver = c.driver.unwrap_or(driver);
+        let driver = c.driver.or(c.booking).unwrap_or(driver);
         Self {
             model: c.model,
             skin: c.skin,
@@ -116,7 +234,8 @@ impl From<&Car> for JsonCar {
         };
 
         let is_connected = c.driver.is_some();
-        let driver = c.driver.as_ref().unwrap_or(&driver);
+        // booked drivers show up before they join
+        let driver = c.driver.as_ref().or(c.booking.as_ref()).unwrap_or(&driver);
         Self {
             model: c.model.clone(),
             skin: c.skin.clone(),
@@ -146,31 +265,158 @@ impl Cars {
                 damage2: 0.0,
                 damage3: 0.0,
                 damage4: 0.0,
+                laps: 0,
+                tyre_compound: String::default(),
+                mandatory_pit: false,
+                ballast_kg: 0.0,
+                restrictor: 0.0,
+                distance_driven: 0.0,
+                contacts: 0,
+                reservation: None,
+                entry_guid: c.guid.clone(),
+                booking: None,
             })
             .collect();
 
         Cars {
-            0: Mutex::new(cars),
+            cars: Mutex::new(cars),
+            reconnect_grace_period: config.server.reconnect_grace_period,
         }
     }
 
+    /// Assigns a car to `driver`. A driver that is already connected or
+    /// reconnects within the grace period gets their old car back with its progress.
     pub fn try_add_car(&self, req: String, driver: Driver) -> Result<(usize, Car), NoSlotsForCar> {
         let mut cars = self.lock().unwrap();
+        if let Some((i, car)) = cars.iter_mut().enumerate().find(|(_i, car)| {
+            car.model == req && car.is_held_by(&driver.guid, self.reconnect_grace_period)
+        }) {
+            log::debug!("Reconnecting car {} for {}", car.model, driver.name);
+            car.driver = Some(driver);
+            car.reservation = None;
+            return Ok((i, car.clone()));
+        }
         for (i, car) in cars.iter_mut().enumerate() {
-            if car.model == req && car.driver.is_none() {
+            if car.model == req && car.is_free(&driver.guid, self.reconnect_grace_period) {
                 log::debug!("Adding car {} for {}", car.model, driver.name);
                 car.driver = Some(driver);
+                car.reservation = None;
+                car.reset_progress();
                 return Ok((i, car.clone()));
             }
         }
         Err(NoSlotsForCar)
     }
+    /// Returns true if a car is still held for `guid`, which then gets it back
+    /// even if the session is closed to new drivers.
+    pub fn is_held_for(&self, guid: &str) -> bool {
+        self.lock()
+            .unwrap()
+            .iter()
+            .any(|car| car.is_held_by(guid, self.reconnect_grace_period))
+    }
+
+    /// Frees the car and keeps it reserved for the driver for the reconnect grace period.
     pub fn remove_car(&self, id: usize) {
         if let Some(car) = self.lock().unwrap().get_mut(id) {
-            if let Some(driver) = &car.driver {
+            if let Some(driver) = car.driver.take() {
                 log::debug!("Removing car {} from driver {}", car.model, driver.name);
+                car.reservation = Some(Reservation {
+                    guid: driver.guid,
+                    since: Instant::now(),
+                });
             };
+        }
+    }
+
+    /// Frees the car without keeping it for its last driver.
+    pub fn free_car(&self, id: usize) {
+        if let Some(car) = self.lock().unwrap().get_mut(id) {
             car.driver = None;
+            car.reservation = None;
+        }
+    }
+
+    /// Books a car of `model`, or of any model without one, for `driver` until they
+    /// cancel. Booking again returns the car booked before.
+    pub fn book(&self, model: Option<&str>, driver: Driver) -> Result<usize, BookingError> {
+        let mut cars = self.lock().unwrap();
+        if let Some(i) = cars.iter().position(|car| car.is_booked_for(&driver.guid)) {
+            return Ok(i);
+        }
+        if let Some(model) = model {
+            if !cars.iter().any(|car| car.model == model) {
+                return Err(BookingError::IllegalCar);
+            }
+        }
+        let grace_period = self.reconnect_grace_period;
+        let car = cars
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, car)| model.is_none_or(|model| car.model == model))
+            .find(|(_, car)| car.booking.is_none() && car.is_free(&driver.guid, grace_period));
+        match car {
+            Some((i, car)) => {
+                log::debug!("{} booked car {} {}", driver.name, i, car.model);
+                car.booking = Some(driver);
+                Ok(i)
+            }
+            None => Err(BookingError::ServerFull),
+        }
+    }
+
+    /// Cancels the booking of `guid`, returns false if they had none.
+    pub fn unbook(&self, guid: &str) -> bool {
+        let mut cars = self.lock().unwrap();
+        let booked = cars.iter_mut().find(|car| {
+            car.booking
+                .as_ref()
+                .is_some_and(|booking| booking.guid == guid)
+        });
+        match booked {
+            Some(car) => {
+                car.booking = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Clears the bookings once the booking session is over. Drivers who have not
+    /// joined yet keep their car for the reconnect grace period.
+    pub fn end_booking(&self) {
+        for car in self.lock().unwrap().iter_mut() {
+            if let Some(booking) = car.booking.take() {
+                if car.driver.is_none() {
+                    car.reservation = Some(Reservation {
+                        guid: booking.guid,
+                        since: Instant::now(),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Cars reserved by the entry list or booked, as listed by `/ENTRY`.
+    pub fn to_entry_list(&self) -> Vec<JsonCar> {
+        self.lock()
+            .unwrap()
+            .iter()
+            .filter(|car| car.entry_guid.is_some() || car.booking.is_some())
+            .map(JsonCar::from)
+            .collect()
+    }
+
+    /// Updates the entry list reservations, `entries` must list the same cars.
+    pub fn update_entry_list(&self, entries: &[ConfigCar]) {
+        for (car, entry) in self.lock().unwrap().iter_mut().zip(entries) {
+            car.entry_guid = entry.guid.clone();
+        }
+    }
+
+    pub fn save_progress(&self, id: usize, status: &ClientStatus) {
+        if let Some(car) = self.lock().unwrap().get_mut(id) {
+            car.save_progress(status);
         }
     }
     pub fn num_of_clients(&self) -> u16 {
@@ -193,14 +439,26 @@ impl Cars {
         self.lock().unwrap().len() as u16
     }
 
-    pub fn to_json(&self) -> Vec<JsonCar> {
+    /// Cars as listed by `/JSON`, `requested_guid` marks the cars booked for that driver.
+    pub fn to_json(&self, requested_guid: Option<&str>) -> Vec<JsonCar> {
         self.lock()
             .unwrap()
             .iter()
-            .map(|v| JsonCar::from(v))
+            .map(|v| JsonCar {
+                is_requested_guid: requested_guid.is_some_and(|guid| v.is_booked_for(guid)),
+                ..JsonCar::from(v)
+            })
             .collect()
     }
 
+    /// True when the entry list reserves every car, so only those drivers can join.
+    pub fn is_locked(&self) -> bool {
+        self.lock()
+            .unwrap()
+            .iter()
+            .all(|car| car.entry_guid.is_some())
+    }
+
     pub fn to_packet(&self, from_session_id: u8) -> CarList {
         let driver = Driver {
             name: "".into(),
@@ -241,3 +499,102 @@ impl Cars {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cars(grace_period: Duration) -> Cars {
+        let mut config: Config = toml::from_str(include_str!("../config.toml")).unwrap();
+        config.server.reconnect_grace_period = grace_period;
+        Cars::new(Arc::new(config))
+    }
+
+    fn driver(guid: &str) -> Driver {
+        Driver {
+            name: guid.into(),
+            team: "".into(),
+            nation: "".into(),
+            guid: guid.into(),
+        }
+    }
+
+    #[test]
+    fn booked_car_waits_for_its_driver() {
+        let cars = cars(Duration::from_secs(60));
+        let model = cars.cars()[0].clone();
+        assert_eq!(
+            cars.book(Some("unknown"), driver("1")),
+            Err(BookingError::IllegalCar)
+        );
+        let booked = cars.book(Some(&model), driver("1")).unwrap();
+        assert_eq!(cars.book(None, driver("1")), Ok(booked));
+
+        let (other, _) = cars.try_add_car(model.clone(), driver("2")).unwrap();
+        assert_ne!(other, booked);
+        let (id, _) = cars.try_add_car(model.clone(), driver("1")).unwrap();
+        assert_eq!(id, booked);
+
+        assert!(!cars.unbook("2"));
+        assert!(cars.unbook("1"));
+        assert_eq!(cars.to_entry_list().len(), 0);
+    }
+
+    #[test]
+    fn unused_bookings_end_with_the_booking_session() {
+        let cars = cars(Duration::ZERO);
+        let model = cars.cars()[0].clone();
+        let joined = cars.book(Some(&model), driver("1")).unwrap();
+        let unused = cars.book(Some(&model), driver("2")).unwrap();
+        cars.try_add_car(model.clone(), driver("1")).unwrap();
+
+        cars.end_booking();
+        assert_eq!(cars.to_entry_list().len(), 0);
+        // without a grace period the unused car is free right away
+        let (id, _) = cars.try_add_car(model.clone(), driver("3")).unwrap();
+        assert_eq!(id, unused);
+        assert_ne!(id, joined);
+    }
+
+    #[test]
+    fn reconnect_keeps_slot_and_progress() {
+        let cars = cars(Duration::from_secs(60));
+        let model = cars.cars()[0].clone();
+        let (first, _) = cars.try_add_car(model.clone(), driver("1")).unwrap();
+        let (second, _) = cars.try_add_car(model.clone(), driver("2")).unwrap();
+        let status = ClientStatus {
+            laps: 3,
+            contacts: 2,
+            distance_driven: 1500.0,
+            mandatory_pit: true,
+            ..Default::default()
+        };
+        cars.save_progress(second, &status);
+        cars.remove_car(second);
+
+        let (other, _) = cars.try_add_car(model.clone(), driver("3")).unwrap();
+        assert!(other != first && other != second);
+
+        let (id, car) = cars.try_add_car(model, driver("2")).unwrap();
+        assert_eq!(id, second);
+        let mut status = ClientStatus::default();
+        status.restore(&car);
+        assert_eq!(status.laps, 3);
+        assert_eq!(status.contacts, 2);
+        assert_eq!(status.distance_driven, 1500.0);
+        assert!(status.mandatory_pit);
+    }
+
+    #[test]
+    fn expired_reservation_is_freed() {
+        let cars = cars(Duration::from_secs(0));
+        let model = cars.cars()[0].clone();
+        let (id, _) = cars.try_add_car(model.clone(), driver("1")).unwrap();
+        cars.lock().unwrap()[id].laps = 3;
+        cars.remove_car(id);
+
+        let (other, car) = cars.try_add_car(model, driver("2")).unwrap();
+        assert_eq!(other, id);
+        assert_eq!(car.laps, 0);
+    }
+}
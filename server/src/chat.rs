@@ -0,0 +1,362 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use protocol::packets::server::{Chat, TestServer};
+
+use crate::config::{BannedWordAction, ChatOptions, Config};
+use crate::event::ServerEvent;
+use crate::server::Server;
+use crate::system::SystemExecutor;
+
+/// What happens to a chat message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Send(String),
+    /// Not sent, the sender is told why if there is a reason.
+    Drop(Option<String>),
+}
+
+/// Keeps drivers from flooding or abusing the chat and logs what they say.
+/// Mutes and rate limits follow the guid, so reconnecting does not reset them.
+#[derive(Debug, Default)]
+pub struct Moderation {
+    muted: HashSet<String>,
+    recent: HashMap<String, VecDeque<Instant>>,
+    log: Option<File>,
+}
+
+impl Moderation {
+    /// Opens `chat.log_file` for appending if it is set.
+    pub fn new(chat: &ChatOptions) -> anyhow::Result<Self> {
+        let log = match &chat.log_file {
+            Some(path) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("failed to open chat log {}", path.display()))?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            log,
+            ..Default::default()
+        })
+    }
+
+    pub fn mute(&mut self, guid: &str) {
+        self.muted.insert(guid.to_string());
+    }
+
+    /// Returns false if the driver was not muted.
+    pub fn unmute(&mut self, guid: &str) -> bool {
+        self.muted.remove(guid)
+    }
+
+    /// Checks `message` from `guid` against the mutes, the rate limit,
+    /// the length limit and the banned words, in that order.
+    pub fn check(&mut self, chat: &ChatOptions, guid: &str, message: &str) -> Verdict {
+        if self.muted.contains(guid) {
+            return Verdict::Drop(Some("You are muted".into()));
+        }
+        if self.is_rate_limited(chat, guid) {
+            return Verdict::Drop(Some("You are sending messages too fast".into()));
+        }
+        if chat.max_length > 0 && message.chars().count() > chat.max_length {
+            return Verdict::Drop(Some(format!(
+                "Messages can be at most {} characters",
+                chat.max_length
+            )));
+        }
+        match censor(message, &chat.banned_words) {
+            None => Verdict::Send(message.to_string()),
+            Some(censored) => match chat.banned_word_action {
+                BannedWordAction::Replace => Verdict::Send(censored),
+                BannedWordAction::Drop => Verdict::Drop(None),
+                BannedWordAction::Warn => {
+                    Verdict::Drop(Some("Watch your language, the message was not sent".into()))
+                }
+            },
+        }
+    }
+
+    /// Counts a message from `guid`, returns true if it is over `chat.max_messages`
+    /// per `chat.rate_window` and should not go through.
+    pub fn is_rate_limited(&mut self, chat: &ChatOptions, guid: &str) -> bool {
+        if chat.max_messages == 0 {
+            return false;
+        }
+        let now = Instant::now();
+        let recent = self.recent.entry(guid.to_string()).or_default();
+        while recent
+            .front()
+            .is_some_and(|sent| now.duration_since(*sent) >= chat.rate_window)
+        {
+            recent.pop_front();
+        }
+        if recent.len() >= chat.max_messages as usize {
+            return true;
+        }
+        recent.push_back(now);
+        false
+    }
+
+    /// Appends a tab separated line to the chat log: time, guid, car, driver,
+    /// what was done with the message and the message as it was written.
+    pub fn log(&mut self, guid: &str, car_id: usize, name: &str, verdict: &Verdict, message: &str) {
+        let file = match &mut self.log {
+            Some(file) => file,
+            None => return,
+        };
+        let action = match verdict {
+            Verdict::Send(sent) if sent == message => "sent",
+            Verdict::Send(_) => "censored",
+            Verdict::Drop(_) => "dropped",
+        };
+        let line = format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\n",
+            timestamp(SystemTime::now()),
+            guid,
+            car_id,
+            name,
+            action,
+            message.replace(['\t', '\n'], " ")
+        );
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            log::warn!("Could not write the chat log: {}", e);
+        }
+    }
+}
+
+/// Replaces every banned word in `message` with asterisks, `None` if there were none.
+/// Words are compared whole and ignoring case.
+fn censor(message: &str, banned_words: &[String]) -> Option<String> {
+    if banned_words.is_empty() {
+        return None;
+    }
+    let mut censored = String::with_capacity(message.len());
+    let mut found = false;
+    let mut rest = message;
+    while !rest.is_empty() {
+        let end = rest
+            .find(|c: char| !c.is_alphanumeric())
+            .unwrap_or(rest.len());
+        let (word, after) = rest.split_at(end);
+        if banned_words
+            .iter()
+            .any(|banned| banned.to_lowercase() == word.to_lowercase())
+        {
+            found = true;
+            censored.extend(word.chars().map(|_| '*'));
+        } else {
+            censored.push_str(word);
+        }
+        let separator = after.chars().next().map_or(0, char::len_utf8);
+        censored.push_str(&after[..separator]);
+        rest = &after[separator..];
+    }
+    found.then_some(censored)
+}
+
+/// Formats `time` as UTC like `2021-03-04T05:06:07Z`.
+fn timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // days to a civil date, http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Passes a chat message from `car_id` through the moderation and sends
+/// what is left of it to everyone else.
+pub fn handle_chat(server: &mut Server, car_id: usize, message: &str) {
+    let guid = match server.clients.get_from_car_id(car_id) {
+        Some(client) => client.guid.clone(),
+        None => return,
+    };
+    let name = driver_name(server, car_id);
+
+    let verdict = server.moderation.check(&server.config.chat, &guid, message);
+    server
+        .moderation
+        .log(&guid, car_id, &name, &verdict, message);
+    match verdict {
+        Verdict::Send(message) => {
+            log::debug!("{}: {}", car_id, message);
+            server.emit(ServerEvent::Chat {
+                car_id,
+                message: message.clone(),
+            });
+            let packet = TestServer::Chat(Chat {
+                car_id: car_id as u8,
+                msg: message,
+            });
+            server.broadcast_with(|c| {
+                if c.car_id != car_id {
+                    c.send_packet(packet.clone());
+                }
+            });
+        }
+        Verdict::Drop(reason) => {
+            log::debug!("{}: {} (dropped)", car_id, message);
+            if let Some(reason) = reason {
+                server.send_chat(car_id, reason);
+            }
+        }
+    }
+}
+
+/// Name of the driver in `car_id` as the chat log shows it.
+pub fn driver_name(server: &Server, car_id: usize) -> String {
+    server
+        .cars
+        .lock()
+        .unwrap()
+        .get(car_id)
+        .and_then(|car| car.driver.as_ref())
+        .map(|driver| driver.name.clone())
+        .unwrap_or_default()
+}
+
+/// Reads the lines of `chat.motd_file` sent to drivers when they join.
+/// The file is read on every join so it can be changed while the server runs.
+pub fn motd(config: &Config) -> Vec<String> {
+    let path = match &config.chat.motd_file {
+        Some(path) => path,
+        None => return Vec::new(),
+    };
+    match fs::read_to_string(path) {
+        Ok(motd) => motd
+            .lines()
+            .map(str::trim_end)
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect(),
+        Err(e) => {
+            log::warn!("Could not read motd {}: {}", path.display(), e);
+            Vec::new()
+        }
+    }
+}
+
+pub fn register(systems: &mut SystemExecutor<Server>) {
+    // when each announcement is due next, `None` once a one-off has been sent
+    let mut due: Option<Vec<Option<Instant>>> = None;
+    systems.add_system("announcements", move |server| {
+        let due = due.get_or_insert_with(|| {
+            server
+                .config
+                .chat
+                .announcements
+                .iter()
+                .map(|announcement| Some(server.start_time + announcement.delay))
+                .collect()
+        });
+        let now = Instant::now();
+        for (announcement, due) in server.config.chat.announcements.iter().zip(due) {
+            let at = match *due {
+                Some(at) if at <= now => at,
+                _ => continue,
+            };
+            server.broadcast_chat(&announcement.message);
+            *due = match announcement.interval.is_zero() {
+                true => None,
+                false => {
+                    let mut next = at + announcement.interval;
+                    // skip the ones missed while the server was busy
+                    while next <= now {
+                        next += announcement.interval;
+                    }
+                    Some(next)
+                }
+            };
+        }
+        Ok(())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn chat_options() -> ChatOptions {
+        let mut chat = Config::default().chat;
+        chat.max_messages = 2;
+        chat.rate_window = Duration::from_secs(60);
+        chat.max_length = 20;
+        chat.banned_words = vec!["Darn".into()];
+        chat
+    }
+
+    #[test]
+    fn moderates_chat() {
+        let mut chat = chat_options();
+        let mut moderation = Moderation::default();
+        assert_eq!(
+            moderation.check(&chat, "a", "darn it, DARN! darned"),
+            Verdict::Drop(Some("Messages can be at most 20 characters".into()))
+        );
+        assert_eq!(
+            moderation.check(&chat, "a", "darn it, DARNed"),
+            Verdict::Send("**** it, DARNed".into())
+        );
+        assert_eq!(
+            moderation.check(&chat, "a", "hi"),
+            Verdict::Drop(Some("You are sending messages too fast".into()))
+        );
+        chat.banned_word_action = BannedWordAction::Drop;
+        assert_eq!(moderation.check(&chat, "b", "darn"), Verdict::Drop(None));
+
+        moderation.mute("b");
+        assert_eq!(
+            moderation.check(&chat, "b", "hi"),
+            Verdict::Drop(Some("You are muted".into()))
+        );
+        assert!(moderation.unmute("b"));
+        assert_eq!(
+            moderation.check(&chat, "b", "hi"),
+            Verdict::Send("hi".into())
+        );
+    }
+
+    #[test]
+    fn formats_utc_timestamps() {
+        let time = UNIX_EPOCH + Duration::from_secs(1614834367);
+        assert_eq!(timestamp(time), "2021-03-04T05:06:07Z");
+    }
+
+    #[test]
+    fn motd_skips_empty_lines() {
+        let path = std::env::temp_dir().join(format!("motd-{}.txt", std::process::id()));
+        fs::write(&path, "Welcome!\r\n\nNo wrecking  \n").unwrap();
+        let mut config = Config::default();
+        assert!(motd(&config).is_empty());
+
+        config.chat.motd_file = Some(path.clone());
+        assert_eq!(motd(&config), vec!["Welcome!", "No wrecking"]);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -8,19 +8,27 @@ use std::{
     time::Instant,
 };
 
-use crate::{option::ServerOptions, udpserver::UdpServerMessage};
-use crate::{server::NewPlayer, udpserver::UdpClientMessage};
+use crate::{car::Car, metrics::Metrics, option::ServerOptions, udpserver::UdpServerMessage};
+use crate::{
+    server::{NewPlayer, SERVER_CAR_ID},
+    udpserver::UdpClientMessage,
+};
 use flume::{Receiver, Sender};
 use protocol::packets::{
     client::{CarUpdate, LapCompleted, TestClient},
     common::Vec3f,
     server::{
-        Bop, Bops, ChangeTireCompound, ClientDisconnect, DamageUpdate, MandatoryPit, P2PCount,
-        PositionUpdate, TestServer, UpdateSession,
+        Bop, Bops, ChangeTireCompound, Chat, ClientDisconnect, DamageUpdate, MandatoryPit,
+        P2PCount, PositionUpdate, TestServer, UpdateSession,
     },
 };
 use slab::Slab;
 use std::cell::Ref;
+use tokio::task::AbortHandle;
+
+/// Longest distance in meters a car can plausibly move between two updates.
+const MAX_UPDATE_DISTANCE: f32 = 100.0;
+
 #[derive(Debug)]
 pub struct Client {
     packets_to_send: Sender<TestServer>,
@@ -34,8 +42,10 @@ pub struct Client {
     //pub p2p_count: Cell<i16>,
     disconnected: Cell<bool>,
     pub has_sent_first_update: Cell<bool>,
-    booked_as_admin: bool,
+    is_admin: Cell<bool>,
     status: RefCell<ClientStatus>,
+    tcp_reader: AbortHandle,
+    metrics: Arc<Metrics>,
 }
 #[derive(Debug)]
 pub struct ClientStatus {
@@ -65,6 +75,9 @@ pub struct ClientStatus {
     pub last_pong_time: Instant,
     pub ping: u32,
     pub time_offset: u32,
+    /// Meters driven, used to check contacts per km.
+    pub distance_driven: f32,
+    pub contacts: u32,
 }
 
 impl Default for ClientStatus {
@@ -96,12 +109,24 @@ impl Default for ClientStatus {
             ping: Default::default(),
             time_offset: Default::default(),
             last_pong_time: Instant::now(),
+            distance_driven: Default::default(),
+            contacts: Default::default(),
         }
     }
 }
 
 impl ClientStatus {
     fn update(&mut self, u: CarUpdate) {
+        let (x, y, z) = (
+            u.pos.x - self.pos.x,
+            u.pos.y - self.pos.y,
+            u.pos.z - self.pos.z,
+        );
+        let moved = (x * x + y * y + z * z).sqrt();
+        // bigger jumps are teleports back to the pits
+        if moved < MAX_UPDATE_DISTANCE {
+            self.distance_driven += moved;
+        }
         self.pos = u.pos;
         self.rotation = u.rotation;
         self.velocity = u.velocity;
@@ -122,6 +147,24 @@ impl ClientStatus {
         self.performance_delta = u.performance_delta;
         self.gas = u.gas;
     }
+
+    /// Restores the race progress kept in the car slot, used when a driver reconnects.
+    pub fn restore(&mut self, car: &Car) {
+        self.laps = car.laps;
+        self.damage_zone_level = [
+            car.damage,
+            car.damage1,
+            car.damage2,
+            car.damage3,
+            car.damage4,
+        ];
+        self.current_tyre_compound = car.tyre_compound.clone();
+        self.mandatory_pit = car.mandatory_pit;
+        self.ballast_kg = car.ballast_kg;
+        self.restrictor = car.restrictor;
+        self.distance_driven = car.distance_driven;
+        self.contacts = car.contacts;
+    }
 }
 
 impl From<&Client> for PositionUpdate {
@@ -223,10 +266,12 @@ impl Client {
             ip: player.ip,
             udp: None.into(),
             has_sent_first_update: false.into(),
-            booked_as_admin: player.booked_as_admin,
+            is_admin: player.booked_as_admin.into(),
             udp_packets_to_send: player.udp_packets_to_send,
             status: RefCell::new(ClientStatus::default()),
             has_valid_checksum: false.into(),
+            tcp_reader: player.tcp_reader,
+            metrics: player.metrics,
         }
     }
 
@@ -266,14 +311,24 @@ impl Client {
 
     pub fn send_udp_packet(&self, packet: TestServer) {
         if let Some(addr) = self.udp() {
-            let _ = self
+            let result = self
                 .udp_packets_to_send
                 .try_send(UdpServerMessage { addr, packet });
+            self.metrics.track_send("udp", result);
         }
     }
+    /// Sends `message` to this driver in the chat, from the server.
+    pub fn send_chat(&self, message: impl Into<String>) {
+        self.send_packet(TestServer::Chat(Chat {
+            car_id: SERVER_CAR_ID,
+            msg: message.into(),
+        }));
+    }
+
     pub fn send_packet(&self, packet: TestServer) {
         log::debug!("Sending:{:?}", packet);
-        let _ = self.packets_to_send.try_send(packet);
+        let result = self.packets_to_send.try_send(packet);
+        self.metrics.track_send("tcp", result);
     }
     pub fn received_packets(&self) -> impl Iterator<Item = TestClient> + '_ {
         self.received_packets.try_iter()
@@ -283,9 +338,32 @@ impl Client {
         self.udp.set(Some(udp));
     }
 
+    /// Marks the client for removal, the server tears it down at the end of the tick.
     pub fn disconnect(&self) {
         self.disconnected.set(true);
     }
+
+    /// Admins joined with the admin password or logged in with `/admin`.
+    pub fn is_admin(&self) -> bool {
+        self.is_admin.get()
+    }
+
+    pub fn set_admin(&self, is_admin: bool) {
+        self.is_admin.set(is_admin);
+    }
+
+    /// Returns true if the client was marked disconnected or either TCP task has stopped.
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected.get()
+            || self.received_packets.is_disconnected()
+            || self.packets_to_send.is_disconnected()
+    }
+
+    /// Stops reading from the TCP connection. Packets already queued
+    /// are still written before the connection closes.
+    pub fn close(&self) {
+        self.tcp_reader.abort();
+    }
     pub fn p2p_count_dec(&self) {
         let p2p = self.status().p2p_count;
         self.status_mut().p2p_count = p2p.saturating_sub(-1);
@@ -323,11 +401,28 @@ impl Clients {
         self.arena.remove(id.0)
     }
 
-    pub fn get_from_ip(&self, ip: IpAddr) -> Option<&Client> {
+    /// Finds the client that registered `addr` with `UpdateUpdAddress`.
+    pub fn get_from_udp(&self, addr: SocketAddr) -> Option<&Client> {
+        self.iter().find(|client| client.udp() == Some(addr))
+    }
+
+    pub fn get_from_car_id(&self, car_id: usize) -> Option<&Client> {
+        self.iter().find(|client| client.car_id == car_id)
+    }
+
+    pub fn get_id_from_guid(&self, guid: &str) -> Option<ClientId> {
+        self.arena
+            .iter()
+            .find(|(_i, client)| client.guid == guid)
+            .map(|(i, _client)| ClientId(i))
+    }
+
+    pub fn disconnected(&self) -> Vec<ClientId> {
         self.arena
             .iter()
-            .find(|(_i, client)| client.ip == ip)
-            .map(|(_i, client)| client)
+            .filter(|(_i, client)| client.is_disconnected())
+            .map(|(i, _client)| ClientId(i))
+            .collect()
     }
 
     pub fn get(&self, id: ClientId) -> Option<&Client> {
@@ -1,16 +1,23 @@
-use anyhow::{bail, Context};
+use anyhow::Context;
 
-use serde::{Deserialize, Deserializer};
-use std::{fs, net::Ipv4Addr, path::Path, str::FromStr, time::Duration};
+use crate::session::SessionOpenType;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    fs,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Wind {
     pub base_speed_min: i32,
     pub base_speed_max: i32,
     pub base_direction: i32,
     pub variation_direction: i32,
 }
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Weather {
     pub graphics: String,
     pub base_ambient: f32,
@@ -20,20 +27,39 @@ pub struct Weather {
     pub wind: Wind,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Session {
     pub name: String,
     pub session_type: u8,
     pub time: u16,
     pub laps: u16,
-    //   pub is_open: bool,
+    /// When drivers can join: "open", "closed" or "wait_only".
+    #[serde(default)]
+    pub is_open: SessionOpenType,
+    /// Time between the session being announced and it starting.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub wait_time: Duration,
+    /// Index into `weathers` used for the whole session instead of a random one.
+    pub weather: Option<usize>,
+    /// Grip the session starts with, overriding the dynamic track.
+    pub grip: Option<f32>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Sessions {
-    #[serde(deserialize_with = "deserialize_duration")]
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
     pub result_screen_time: Duration,
-    #[serde(deserialize_with = "deserialize_duration")]
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
     pub race_over_time: Duration,
     pub sessions: Vec<Session>,
 }
@@ -46,7 +72,7 @@ impl std::ops::Deref for Sessions {
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct DynamicTrack {
     pub enabled: bool,
     pub base_grip: f32,
@@ -56,7 +82,7 @@ pub struct DynamicTrack {
     pub session_transfer: f32,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct GameOptions {
     pub legal_tyres: String,
     pub force_virtual_mirror: bool,
@@ -70,12 +96,28 @@ pub struct GameOptions {
     pub start_rule: u8,
     pub password: Option<String>,
     pub admin_password: Option<String>,
+    /// Drivers that cannot join the server.
+    #[serde(default)]
+    pub banned_guids: Vec<String>,
     pub damage_multiplier: f32,
     pub max_contacts_per_km: u8,
     pub allowed_tyres: i16,
-    #[serde(deserialize_with = "deserialize_duration")]
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
     pub vote_duration: Duration,
+    /// Percentage of drivers needed to pass a session vote.
+    #[serde(default = "default_voting_quorum")]
+    pub voting_quorum: u8,
+    /// Percentage of drivers needed to pass a kick vote.
+    #[serde(default = "default_kick_quorum")]
+    pub kick_quorum: u8,
+    /// Timed races go on for one more lap after the time is up.
     pub has_extra_lap: bool,
+    /// Grid positions reversed for a second race, -1 reverses every position.
+    #[serde(default)]
+    pub inverted_grid_positions: i16,
     pub pit_window_start: u16,
     pub pit_window_end: u16,
     pub race_gas_penalty_disabled: bool,
@@ -87,7 +129,7 @@ impl GameOptions {
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct ServerOptions {
     pub name: String,
     pub address: Ipv4Addr,
@@ -96,21 +138,142 @@ pub struct ServerOptions {
     pub http_port: u16,
     pub max_clients: u16,
     pub welcome_message: String,
+    /// Country name and code shown in the lobby, like `["Finland", "FI"]`.
+    #[serde(default = "default_country")]
+    pub country: [String; 2],
     pub client_send_interval_hz: u8,
+    #[serde(
+        default = "default_reconnect_grace_period",
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub reconnect_grace_period: Duration,
+    /// Directory session results are written to.
+    #[serde(default = "default_results_dir")]
+    pub results_dir: PathBuf,
+    /// The `content` directory of the game, with the `cars` and `tracks` used.
+    /// Checksums and content checks are skipped without it.
+    pub content_dir: Option<PathBuf>,
+    /// Bearer token of the admin API at `/api`. The admin password works as well,
+    /// without either the API is disabled.
+    pub admin_token: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdateMode {
+    /// Distance between car positions in meters.
+    Distance,
+    /// Gap between cars along the track spline.
+    Spline,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct UpdateOptions {
+    pub mode: UpdateMode,
+    pub near_distance: f32,
+    pub near_spline_gap: f32,
+    pub far_update_interval: u8,
+    pub max_packet_size: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LiveTimingOptions {
+    /// How often car positions and standings are pushed to `/live`,
+    /// laps and sectors are sent right away.
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub interval: Duration,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LobbyOptions {
+    /// Lists the server in the game's server browser.
+    pub register_to_lobby: bool,
+    /// Base URL the `register` and `ping` calls are made to.
+    pub url: String,
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub ping_interval: Duration,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChatOptions {
+    /// File whose lines are sent in the chat to drivers joining the server.
+    pub motd_file: Option<PathBuf>,
+    pub announcements: Vec<Announcement>,
+    /// Longest message in characters, 0 for no limit.
+    pub max_length: usize,
+    /// Messages a driver can send within `rate_window`, 0 for no limit.
+    pub max_messages: u32,
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub rate_window: Duration,
+    /// Words matched whole and ignoring case.
+    pub banned_words: Vec<String>,
+    pub banned_word_action: BannedWordAction,
+    /// Every message is appended here with the time and guid of the sender.
+    pub log_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BannedWordAction {
+    /// Sends the message with the banned words replaced by asterisks.
+    Replace,
+    /// Silently drops the message.
+    Drop,
+    /// Drops the message and warns the sender.
+    Warn,
+}
+
+/// A message the server sends to everyone in the chat.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Announcement {
+    pub message: String,
+    /// Time after the server starts until the first announcement.
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub delay: Duration,
+    /// Repeats the announcement this often, 0 announces only once.
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub interval: Duration,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PluginOptions {
+    pub address: SocketAddr,
+    pub local_port: u16,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Log {
-    #[serde(deserialize_with = "deserialize_log_level")]
+    #[serde(
+        deserialize_with = "deserialize_log_level",
+        serialize_with = "serialize_log_level"
+    )]
     pub level: log::LevelFilter,
 }
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Car {
     pub model: String,
     pub skin: String,
+    /// Reserves the car for the driver with this guid.
+    pub guid: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Config {
     pub server: ServerOptions,
     pub game: GameOptions,
@@ -120,17 +283,96 @@ pub struct Config {
     pub sun_angle: f32,
     pub time_of_day_multiplier: f32,
     pub track: String,
+    /// Layout of `track`, empty for tracks with only one.
+    #[serde(default)]
+    pub track_config: String,
     pub cars: Vec<Car>,
     pub log: Log,
+    #[serde(default)]
+    pub updates: UpdateOptions,
+    #[serde(default)]
+    pub lobby: LobbyOptions,
+    #[serde(default)]
+    pub live_timing: LiveTimingOptions,
+    #[serde(default)]
+    pub chat: ChatOptions,
+    pub plugin: Option<PluginOptions>,
+}
+
+// Defaults of the settings added after the first config.toml, so older files keep
+// working. They match the values in the default config.toml.
+
+fn default_voting_quorum() -> u8 {
+    75
+}
+
+fn default_kick_quorum() -> u8 {
+    85
+}
+
+fn default_country() -> [String; 2] {
+    ["na".into(), "na".into()]
+}
+
+fn default_reconnect_grace_period() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_results_dir() -> PathBuf {
+    "results".into()
+}
+
+impl Default for UpdateOptions {
+    fn default() -> Self {
+        Self {
+            mode: UpdateMode::Distance,
+            near_distance: 200.0,
+            near_spline_gap: 0.05,
+            far_update_interval: 4,
+            max_packet_size: 1200,
+        }
+    }
+}
+
+impl Default for LobbyOptions {
+    fn default() -> Self {
+        Self {
+            register_to_lobby: false,
+            url: "http://93.57.10.21/lobby.ashx".into(),
+            ping_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Default for LiveTimingOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(250),
+        }
+    }
+}
+
+impl Default for ChatOptions {
+    fn default() -> Self {
+        Self {
+            motd_file: None,
+            announcements: Vec::new(),
+            max_length: 200,
+            max_messages: 5,
+            rate_window: Duration::from_secs(10),
+            banned_words: Vec::new(),
+            banned_word_action: BannedWordAction::Replace,
+            log_file: None,
+        }
+    }
 }
 
 //racewait cannot be lower 20s
 //race over lower than 30s
 //result time lower 30s
 
-const DEFAULT_CONFIG: &str = include_str!("../config.toml");
-
-/// Loads the config, creating a default config if needed.
+/// The default config.toml, comments included.
+pub const DEFAULT_CONFIG: &str = include_str!("../config.toml");
 
 fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
 where
@@ -140,6 +382,20 @@ where
     Ok(Duration::from_millis(millis))
 }
 
+fn serialize_duration<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_millis() as u64)
+}
+
+fn serialize_log_level<S: Serializer>(
+    level: &log::LevelFilter,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&level.to_string().to_lowercase())
+}
+
 fn deserialize_log_level<'de, D: Deserializer<'de>>(
     deserializer: D,
 ) -> Result<log::LevelFilter, D::Error> {
@@ -154,55 +410,71 @@ fn deserialize_log_level<'de, D: Deserializer<'de>>(
 
 impl Config {
     pub fn get_track_config(&self) -> String {
-        "".into() //TODO
+        self.track_config.clone()
+    }
+    /// Loads the config at `path`, see `Config::validate` for checking it.
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Config> {
+        let path = path.as_ref();
+        let config_string = fs::read_to_string(path).with_context(|| {
+            format!(
+                "failed to read {}, `server --print-default-config` prints a config to start from",
+                path.display()
+            )
+        })?;
+        config_string.parse()
     }
-    pub fn load(path: &str) -> anyhow::Result<Config> {
-        let path = Path::new(path);
-        let default_config = DEFAULT_CONFIG;
+}
 
-        if !path.exists() {
-            println!("Creating default config");
-            fs::write(path, default_config)?;
-        }
+impl Config {
+    /// Writes the config in the format read by `Config::load`.
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        // going through a `Value` puts plain values before tables, as TOML requires
+        let value = toml::Value::try_from(self)?;
+        Ok(toml::to_string_pretty(&value)?)
+    }
+}
 
-        let config_string = fs::read_to_string(path)?;
-        let config: Config = toml::from_str(&config_string).context("invalid config.toml file")?;
-
-        for (i, w) in config.weathers.iter().enumerate() {
-            if w.base_road + w.variation_road >= 75.0 {
-                bail!("{}:Road Temperature cannot be over 75c", i)
-            }
-            if w.base_ambient + w.variation_ambient >= 45.0 {
-                bail!("{}:Road Temperature cannot be over 45c", i)
-            }
-
-            if w.wind.base_speed_min > w.wind.base_speed_max {
-                bail!("{}:Windspeed min cannot be bigger than max", i);
-            }
-            if w.wind.base_speed_min > 40 || w.wind.base_speed_max > 40 {
-                bail!("{}:Windspeed cannot be bigger than 40ms/s", i);
-            }
-        }
+impl FromStr for Config {
+    type Err = anyhow::Error;
 
-        for (i, s) in config.sessions.iter().enumerate() {
-            if s.time == 0 {
-                bail!("{}: Session time cannot be 0", i)
-            }
-        }
-        if config.game.pit_window_end <= config.game.pit_window_start
-            && config.game.pit_window_enabled()
-        {
-            bail!("pit_window_end cant be smaller than pit_window_start")
-        }
-        if config.sessions.result_screen_time.as_millis() < 10000 {
-            bail!("result_screen_time cannot be lower than 10000")
-        }
-        if config.sessions.race_over_time.as_millis() < 30000 {
-            bail!("race_over_time cannot be lower than 30000")
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        toml::from_str(s).context("invalid config.toml file")
+    }
+}
+
+impl Default for Config {
+    /// The default config.toml, the one `--print-default-config` prints.
+    fn default() -> Self {
+        DEFAULT_CONFIG
+            .parse()
+            .expect("default config.toml is invalid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_a_config_from_before_the_newer_settings() {
+        let old: Config = include_str!("../tests/old_config.toml").parse().unwrap();
+        let default = Config::default();
+        // the defaults of missing settings are the ones in the default config.toml
+        fn value(section: &impl Serialize) -> toml::Value {
+            toml::Value::try_from(section).unwrap()
         }
-        //if (main.ServerOptions.raceOverTime < 30000) {
-        //session with 0time
-        //race wait under 20000
-        Ok(config)
+        assert_eq!(value(&old.updates), value(&default.updates));
+        assert_eq!(value(&old.lobby), value(&default.lobby));
+        assert_eq!(value(&old.live_timing), value(&default.live_timing));
+        assert_eq!(value(&old.chat), value(&default.chat));
+        assert_eq!(old.server.country, default.server.country);
+        assert_eq!(
+            old.server.reconnect_grace_period,
+            default.server.reconnect_grace_period
+        );
+        assert_eq!(old.server.results_dir, default.server.results_dir);
+        assert_eq!(old.game.voting_quorum, default.game.voting_quorum);
+        assert_eq!(old.game.kick_quorum, default.game.kick_quorum);
+        assert_eq!(old.sessions[0].is_open, default.sessions[0].is_open);
     }
 }
@@ -43,12 +43,24 @@ impl From<&DynamicTrackConfig> for DynamicTrack {
 }
 
 impl DynamicTrack {
+    /// Applies new settings, keeping the grip gained so far.
+    pub fn reconfigure(&mut self, x: &DynamicTrackConfig) {
+        self.enabled = x.enabled;
+        self.session_start_grip = x.session_start_grip;
+        self.base_grip = x.base_grip;
+        self.grip_per_lap = x.grip_per_lap;
+        self.random_grip = x.random_grip;
+        self.session_transfer = x.session_transfer;
+    }
     pub fn on_lap_complete(&mut self) {
         self.laps += 1;
     }
-    pub fn on_new_session(&mut self) {
-        let gained = (self.base_grip - self.grip()) * self.session_transfer;
-        self.gained_grip = gained;
+    /// Carries grip over to the next session, or starts it at `start_grip` when given.
+    pub fn on_new_session(&mut self, start_grip: Option<f32>) {
+        self.gained_grip = match start_grip {
+            Some(grip) => grip - self.base_grip,
+            None => (self.base_grip - self.grip()) * self.session_transfer,
+        };
         self.laps = 0;
     }
     pub fn grip(&self) -> f32 {
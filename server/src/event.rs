@@ -0,0 +1,123 @@
+use tokio::sync::broadcast;
+
+use crate::penalty::PenaltyReason;
+use crate::vote::VoteKind;
+
+/// How many events a slow subscriber can fall behind before it misses some.
+pub const EVENT_CAPACITY: usize = 256;
+
+/// Something that happened on the server, for integrations and subsystems
+/// that want to follow it without hooking into the packet handlers.
+///
+/// The session results and the UDP plugin are still fed by the handlers
+/// directly. A subscriber that falls `EVENT_CAPACITY` events behind misses
+/// some, which the results must not, and the plugin packets carry car and
+/// position data the events leave out.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerEvent {
+    DriverJoined {
+        car_id: usize,
+        name: String,
+        guid: String,
+    },
+    DriverLeft {
+        car_id: usize,
+        name: String,
+        guid: String,
+    },
+    LapCompleted {
+        car_id: usize,
+        laptime: u32,
+        cuts: u8,
+        laps: u32,
+    },
+    SectorSplit {
+        car_id: usize,
+        sector: u8,
+        time: u32,
+        cuts: u8,
+    },
+    /// `other_car` is `None` when the car hit the environment.
+    Collision {
+        car_id: usize,
+        other_car: Option<usize>,
+        impact_speed: f32,
+    },
+    Chat {
+        car_id: usize,
+        message: String,
+    },
+    SessionChanged {
+        index: usize,
+        name: String,
+        session_type: u8,
+    },
+    VoteCast {
+        kind: VoteKind,
+        car_id: usize,
+        yes: bool,
+    },
+    VoteResolved {
+        kind: VoteKind,
+        passed: bool,
+    },
+    Penalty {
+        car_id: usize,
+        reason: PenaltyReason,
+    },
+}
+
+/// Broadcasts `ServerEvent`s to every subscriber.
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<ServerEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+        Self { sender }
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn publish(&self, event: ServerEvent) {
+        // nobody listening is fine
+        let _ = self.sender.send(event);
+    }
+
+    /// Subscribes to events published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.sender.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscribers_receive_published_events() {
+        let bus = EventBus::new();
+        bus.publish(ServerEvent::Chat {
+            car_id: 0,
+            message: "nobody hears this".into(),
+        });
+
+        let mut first = bus.subscribe();
+        let mut second = bus.subscribe();
+        let event = ServerEvent::VoteResolved {
+            kind: VoteKind::NextSession,
+            passed: true,
+        };
+        bus.publish(event.clone());
+
+        assert_eq!(first.try_recv().unwrap(), event);
+        assert_eq!(second.try_recv().unwrap(), event);
+        assert!(first.try_recv().is_err());
+    }
+}
@@ -1,56 +1,110 @@
+use std::fmt::Display;
+use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
+use anyhow::Context;
+
+use flume::Sender;
+use hyper::header::{HeaderValue, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE};
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::{body, Body, Method, Request, Response, Server, StatusCode};
 use protocol::json::{Info, JSON};
+use protocol::packets::server::SessionType;
+use serde_json::{Map, Value};
+use tokio::sync::{broadcast, oneshot};
 
+use crate::admin::{constant_time_eq, AdminCommand, AdminReply, AdminRequest};
+use crate::car::{BookingError, Cars, Driver};
+use crate::config::Config;
+use crate::live::LiveFeed;
+use crate::metrics::Metrics;
 use crate::option::ServerOptions;
-use crate::{car::Cars, config::Config};
+
+/// The race director dashboard served at `/admin`.
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+/// How long an admin API call waits for the server thread to answer.
+const ADMIN_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct HttpServer {
     //config: Arc<Config>,
     //cars: Arc<Cars>,
 }
 
+/// What the request handlers share.
+#[derive(Clone)]
+struct HttpState {
+    config: Arc<Config>,
+    options: Arc<RwLock<ServerOptions>>,
+    cars: Arc<Cars>,
+    start_time: Instant,
+    admin: Sender<AdminRequest>,
+    live: LiveFeed,
+    metrics: Arc<Metrics>,
+}
+
 impl HttpServer {
     pub async fn serve(
         config: Arc<Config>,
         options: Arc<RwLock<ServerOptions>>,
         cars: Arc<Cars>,
-    ) -> anyhow::Result<()> {
+        start_time: Instant,
+        admin: Sender<AdminRequest>,
+        live: LiveFeed,
+        metrics: Arc<Metrics>,
+    ) -> anyhow::Result<SocketAddr> {
         let addr = format!("{}:{}", config.server.address, config.server.http_port)
             .parse()
             .expect("Failed to parse http socket addrs");
 
+        let state = HttpState {
+            config,
+            options,
+            cars,
+            start_time,
+            admin,
+            live,
+            metrics,
+        };
         let make_service = make_service_fn(move |_| {
-            let cars = cars.clone();
-            let config = config.clone();
-            let options = options.clone();
+            let state = state.clone();
             async move {
                 // This is the request handler.
                 Ok::<_, hyper::Error>(service_fn(move |req| {
-                    HttpServer::assetto(req, cars.clone(), options.clone(), config.clone())
+                    HttpServer::assetto(req, state.clone())
                 }))
             }
         });
 
-        let server = Server::bind(&addr).serve(make_service);
+        let server = Server::try_bind(&addr)
+            .context("failed to bind to http port - maybe a server is already running?")?
+            .serve(make_service);
+        let local_addr = server.local_addr();
         tokio::spawn(async move {
             if let Err(err) = server.await {
                 log::error!("Server error: {}", err);
             }
         });
 
-        log::debug!("Listening http on http://{}", addr);
-        Ok(())
+        log::debug!("Listening http on http://{}", local_addr);
+        Ok(local_addr)
     }
 
-    async fn assetto(
-        req: Request<Body>,
-        cars: Arc<Cars>,
-        options: Arc<RwLock<ServerOptions>>,
-        config: Arc<Config>,
-    ) -> anyhow::Result<Response<Body>> {
+    async fn assetto(req: Request<Body>, state: HttpState) -> anyhow::Result<Response<Body>> {
+        if let Some(command) = req.uri().path().strip_prefix("/api/") {
+            let command = command.to_string();
+            return HttpServer::api(req, command, &state).await;
+        }
+        let HttpState {
+            config,
+            options,
+            cars,
+            start_time,
+            live,
+            metrics,
+            ..
+        } = state;
         let decoded = urlencoding::decode(req.uri().path())?;
         let splitted: Vec<&str> = decoded.split("|").collect();
         log::trace!("{:?}", splitted);
@@ -60,65 +114,282 @@ impl HttpServer {
 
             (&Method::GET, "/INFO") => {
                 log::debug!("/INFO");
-                Ok(Response::new(Body::from(HttpServer::info(
-                    config.clone(),
-                    options.clone(),
-                    cars.clone(),
-                ))))
+                let info = HttpServer::info(&config, &options, &cars, start_time);
+                Ok(Response::new(Body::from(serde_json::to_string(&info)?)))
             }
 
+            // the lobby asks for `/JSON|<guid>` to show which cars the player has booked
             (&Method::GET, "/JSON") => {
                 log::debug!("/JSON");
-                Ok(Response::new(Body::from(HttpServer::jsons(cars.clone()))))
+                let guid = splitted.get(1).copied().filter(|guid| !guid.is_empty());
+                Ok(Response::new(Body::from(HttpServer::jsons(
+                    cars.clone(),
+                    guid,
+                ))))
             }
+
+            (&Method::GET, "/live") => Ok(HttpServer::live(&live)),
+
+            // the page itself is public, everything it shows comes from the admin API
+            (&Method::GET, "/admin") => {
+                // available whenever the API it uses is
+                let admin_password = options.read().unwrap().admin_password.is_some();
+                if !admin_password && config.server.admin_token.is_none() {
+                    return Ok(HttpServer::status(StatusCode::NOT_FOUND));
+                }
+                let mut response = Response::new(Body::from(DASHBOARD));
+                response.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/html; charset=utf-8"),
+                );
+                Ok(response)
+            }
+
+            (&Method::GET, "/metrics") => {
+                let mut response = Response::new(Body::from(metrics.render()));
+                response.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/plain; version=0.0.4"),
+                );
+                Ok(response)
+            }
+
+            (&Method::GET, "/ENTRY") => {
+                log::debug!("/ENTRY");
+                let entries = JSON {
+                    cars: cars.to_entry_list(),
+                };
+                Ok(Response::new(Body::from(serde_json::to_string(&entries)?)))
+            }
+
+            (&Method::GET, "/SUB") => {
+                let reply = HttpServer::subscribe(&splitted[1..], &options, &cars);
+                log::debug!("/SUB {}", reply);
+                Ok(Response::new(Body::from(reply)))
+            }
+
+            (&Method::GET, "/UNSUB") => {
+                let guid = splitted.get(1).copied().unwrap_or_default();
+                let reply = HttpServer::unsubscribe(guid, &options, &cars);
+                log::debug!("/UNSUB {}", reply);
+                Ok(Response::new(Body::from(reply)))
+            }
+            _ => Ok(HttpServer::status(StatusCode::NOT_FOUND)),
+        }
+    }
+
+    /// Runs the admin command at `/api/<command>` with the JSON body as its arguments.
+    /// Queries are `GET`, everything else `POST`. The bearer token is `server.admin_token`
+    /// or the admin password.
+    async fn api(
+        req: Request<Body>,
+        command: String,
+        state: &HttpState,
+    ) -> anyhow::Result<Response<Body>> {
+        let token = state.config.server.admin_token.as_deref();
+        let admin_password = state.options.read().unwrap().admin_password.clone();
+        if token.is_none() && admin_password.is_none() {
+            return Ok(HttpServer::status(StatusCode::NOT_FOUND));
+        }
+        // the dashboard logs in with the admin password
+        let bearer = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let matches = |secret: Option<&str>, bearer: &str| {
+            secret.is_some_and(|secret| constant_time_eq(secret.as_bytes(), bearer.as_bytes()))
+        };
+        let authorized = bearer.is_some_and(|bearer| {
+            // both are checked so the time taken does not tell which one matched
+            matches(token, bearer) | matches(admin_password.as_deref(), bearer)
+        });
+        if !authorized {
+            return HttpServer::api_error(StatusCode::UNAUTHORIZED, "invalid token");
+        }
+
+        let method = req.method().clone();
+        let body = body::to_bytes(req.into_body()).await?;
+        let mut args = match body.is_empty() {
+            true => Map::new(),
+            false => match serde_json::from_slice::<Map<String, Value>>(&body) {
+                Ok(args) => args,
+                Err(e) => return HttpServer::api_error(StatusCode::BAD_REQUEST, e),
+            },
+        };
+        args.insert("command".into(), command.into());
+        let command: AdminCommand = match serde_json::from_value(Value::Object(args)) {
+            Ok(command) => command,
+            Err(e) => return HttpServer::api_error(StatusCode::BAD_REQUEST, e),
+        };
+        let expected = match command.is_query() {
+            true => Method::GET,
+            false => Method::POST,
+        };
+        if method != expected {
+            return HttpServer::api_error(
+                StatusCode::METHOD_NOT_ALLOWED,
+                format!("use {}", expected),
+            );
+        }
+
+        log::debug!("/api {:?}", command);
+        let (reply, reply_rx) = oneshot::channel();
+        let _ = state.admin.send(AdminRequest { command, reply });
+        let reply = match tokio::time::timeout(ADMIN_TIMEOUT, reply_rx).await {
+            Ok(Ok(reply)) => reply,
             _ => {
-                let mut not_found = Response::default();
-                *not_found.status_mut() = StatusCode::NOT_FOUND;
-                Ok(not_found)
+                return HttpServer::api_error(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "the server did not answer",
+                )
             }
+        };
+        match reply {
+            Ok(AdminReply::Done) => Ok(HttpServer::status(StatusCode::NO_CONTENT)),
+            Ok(reply) => Ok(Response::new(Body::from(serde_json::to_string(&reply)?))),
+            Err(e) => HttpServer::api_error(StatusCode::BAD_REQUEST, e),
         }
     }
 
-    fn jsons(cars: Arc<Cars>) -> String {
+    fn api_error(status: StatusCode, error: impl Display) -> anyhow::Result<Response<Body>> {
+        let body = serde_json::json!({ "error": error.to_string() });
+        let mut response = Response::new(Body::from(serde_json::to_string(&body)?));
+        *response.status_mut() = status;
+        Ok(response)
+    }
+
+    fn status(status: StatusCode) -> Response<Body> {
+        let mut response = Response::default();
+        *response.status_mut() = status;
+        response
+    }
+
+    /// Streams the live timing feed as server-sent events until the client goes away.
+    fn live(live: &LiveFeed) -> Response<Body> {
+        let (current, mut events) = live.subscribe();
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for sse in current {
+                if sender.send_data(sse.into()).await.is_err() {
+                    return;
+                }
+            }
+            loop {
+                let sse = match events.recv().await {
+                    Ok(sse) => sse,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                if sender.send_data(sse.into()).await.is_err() {
+                    return;
+                }
+            }
+        });
+        let mut response = Response::new(body);
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        response
+    }
+
+    fn jsons(cars: Arc<Cars>, guid: Option<&str>) -> String {
         let p = JSON {
-            cars: cars.to_json(),
+            cars: cars.to_json(guid),
         };
         serde_json::to_string(&p).unwrap()
     }
 
-    fn info(config: Arc<Config>, options: Arc<RwLock<ServerOptions>>, cars: Arc<Cars>) -> String {
-        let p = Info {
+    /// Books a car from `/SUB|<guid>|<name>|<model>|<password>` during the booking
+    /// session, everything after the guid is optional. Replies `OK` or why not.
+    fn subscribe(args: &[&str], options: &RwLock<ServerOptions>, cars: &Cars) -> &'static str {
+        let arg = |i: usize| args.get(i).copied().filter(|arg| !arg.is_empty());
+        let guid = match arg(0) {
+            Some(guid) => guid,
+            None => return "MISSING GUID",
+        };
+        {
+            let options = options.read().unwrap();
+            if options.sessions.get_current_session().session_type != SessionType::Booking {
+                return "CLOSED";
+            }
+            if options.is_banned(guid) {
+                return "BLACKLISTED";
+            }
+            let password = arg(3).unwrap_or_default();
+            let is_admin = options.admin_password.as_deref() == Some(password);
+            if !is_admin && options.password.as_deref().is_some_and(|p| p != password) {
+                return "INCORRECT PASSWORD";
+            }
+        }
+        let driver = Driver {
+            name: arg(1).unwrap_or(guid).into(),
+            team: "".into(),
+            nation: "".into(),
+            guid: guid.into(),
+        };
+        match cars.book(arg(2), driver) {
+            Ok(_) => "OK",
+            Err(BookingError::IllegalCar) => "ILLEGAL CAR",
+            Err(BookingError::ServerFull) => "SERVER FULL",
+        }
+    }
+
+    /// Cancels the booking of `guid` from `/UNSUB|<guid>` during the booking session.
+    fn unsubscribe(guid: &str, options: &RwLock<ServerOptions>, cars: &Cars) -> &'static str {
+        {
+            let options = options.read().unwrap();
+            if options.sessions.get_current_session().session_type != SessionType::Booking {
+                return "CLOSED";
+            }
+        }
+        match cars.unbook(guid) {
+            true => "OK",
+            false => "NOT BOOKED",
+        }
+    }
+
+    /// What the lobby shows about the server, also sent when registering to it.
+    pub fn info(
+        config: &Config,
+        options: &RwLock<ServerOptions>,
+        cars: &Cars,
+        start_time: Instant,
+    ) -> Info {
+        let options = options.read().unwrap();
+        // the lobby lists the event, so the race decides, not the session running now
+        let timed = options
+            .sessions
+            .race()
+            .is_some_and(|race| race.is_timed_race());
+        Info {
+            // the lobby fills in the address it sees
             ip: "".into(),
-            port: config.server.tcp_port,
+            port: config.server.udp_port,
             cport: config.server.http_port,
             name: config.server.name.clone(),
             clients: cars.num_of_clients(),
             maxclients: cars.max_clients(),
             track: config.track.clone(),
             cars: cars.cars(),
-            timeofday: 1337,
-            session: options.read().unwrap().sessions.get_current() as u16,
-            sessiontypes: options.read().unwrap().sessions.get_types(),
-            durations: options.read().unwrap().sessions.get_durations(),
-            timeleft: options
-                .read()
-                .unwrap()
-                .sessions
-                .left_time()
-                .as_secs()
-                .into(),
-            country: vec!["TODO".to_string()],
-            pass: config.game.password.is_some(),
-            timestamp: 0,
+            timeofday: options.sun_angle.get().round() as i64,
+            session: options.sessions.get_current() as u16,
+            sessiontypes: options.sessions.get_types(),
+            durations: options.sessions.get_durations(),
+            timeleft: options.sessions.left_time().as_secs(),
+            country: config.server.country.to_vec(),
+            pass: options.password.is_some(),
+            timestamp: start_time.elapsed().as_millis() as u64,
+            // extra data only the Content Manager servers send
             json: serde_json::Value::Null,
-            l: false,
-            pickup: true,
+            l: cars.is_locked(),
+            // a booking session means drivers cannot just pick up a free car
+            pickup: !options.sessions.get_types().contains(&0),
             tport: config.server.tcp_port,
-            timed: false,
-            extra: false,
-            pit: false,
-            inverted: 0,
-        };
-        serde_json::to_string(&p).unwrap()
+            timed,
+            extra: timed && config.game.has_extra_lap,
+            pit: config.game.pit_window_enabled(),
+            inverted: config.game.inverted_grid_positions,
+        }
     }
 }
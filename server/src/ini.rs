@@ -0,0 +1,531 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+
+use crate::config::{Car, Config, PluginOptions, Session, Weather, Wind};
+use crate::session::SessionOpenType;
+
+/// Session sections in the order the Kunos server runs them, with their session type.
+const SESSIONS: [(&str, u8); 4] = [("BOOK", 0), ("PRACTICE", 1), ("QUALIFY", 2), ("RACE", 3)];
+
+/// Keys and values of one `[SECTION]`, keys are uppercase.
+#[derive(Debug, Default)]
+struct Section {
+    name: String,
+    values: HashMap<String, String>,
+    /// Keys looked up so far, the others were not imported.
+    read: RefCell<HashSet<String>>,
+}
+
+impl Section {
+    fn value(&self, key: &str) -> Option<&String> {
+        self.read.borrow_mut().insert(key.to_string());
+        self.values.get(key)
+    }
+
+    fn get<T>(&self, key: &str) -> anyhow::Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        match self.value(key) {
+            Some(value) if !value.is_empty() => value
+                .parse()
+                .map(Some)
+                .map_err(|e| anyhow!("[{}] {}: {}", self.name, key, e)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Kunos flags are written as 0 and 1.
+    fn flag(&self, key: &str) -> anyhow::Result<Option<bool>> {
+        Ok(self.get::<u8>(key)?.map(|value| value != 0))
+    }
+
+    fn string(&self, key: &str) -> Option<String> {
+        self.value(key).filter(|value| !value.is_empty()).cloned()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Ini {
+    sections: HashMap<String, Section>,
+}
+
+impl Ini {
+    fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.get(name)
+    }
+
+    /// Sections named `prefix` followed by 0, 1, 2... until one is missing.
+    fn numbered(&self, prefix: &str) -> impl Iterator<Item = &Section> + '_ {
+        let prefix = prefix.to_string();
+        (0..).map_while(move |i| self.section(&format!("{}_{}", prefix, i)))
+    }
+
+    /// Every `[SECTION] KEY` that was never looked up, sorted.
+    fn skipped(&self) -> Vec<String> {
+        let mut skipped: Vec<String> = self
+            .sections
+            .values()
+            .flat_map(|section| {
+                let read = section.read.borrow();
+                section
+                    .values
+                    .keys()
+                    .filter(|key| !read.contains(*key))
+                    .map(|key| format!("[{}] {}", section.name, key))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        skipped.sort();
+        skipped
+    }
+}
+
+impl FromStr for Ini {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut ini = Ini::default();
+        let mut current = None;
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                let name = name.trim().to_uppercase();
+                ini.sections.entry(name.clone()).or_insert_with(|| Section {
+                    name: name.clone(),
+                    ..Default::default()
+                });
+                current = Some(name);
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .with_context(|| format!("line {}: expected KEY=VALUE", i + 1))?;
+            let section = current
+                .as_ref()
+                .and_then(|name| ini.sections.get_mut(name))
+                .with_context(|| format!("line {}: value outside of a section", i + 1))?;
+            section
+                .values
+                .insert(key.trim().to_uppercase(), value.trim().to_string());
+        }
+        Ok(ini)
+    }
+}
+
+/// Loads `server_cfg.ini` and `entry_list.ini` from `dir`, the Kunos `cfg` directory.
+pub fn load(dir: &Path) -> anyhow::Result<Config> {
+    let read = |name: &str| {
+        let path = dir.join(name);
+        fs::read_to_string(&path).with_context(|| format!("failed to read {}", path.display()))
+    };
+    let server_cfg = read("server_cfg.ini")?;
+    let entry_list = read("entry_list.ini")?;
+    let mut config = import(&server_cfg, &entry_list)?;
+
+    if !config.server.welcome_message.is_empty() {
+        let path = Path::new(&config.server.welcome_message).to_path_buf();
+        let found = [path.clone(), dir.join(path.file_name().unwrap_or_default())];
+        config.server.welcome_message =
+            match found.iter().find_map(|path| fs::read_to_string(path).ok()) {
+                Some(message) => message.trim().to_string(),
+                None => {
+                    log::warn!("Welcome message {} not found", path.display());
+                    String::new()
+                }
+            };
+    }
+    Ok(config)
+}
+
+/// Maps the contents of `server_cfg.ini` and `entry_list.ini` onto the default config.
+/// Settings the INI files don't have keep their default, the ones the config has no
+/// place for are skipped with a warning. `server.welcome_message` is left as the path
+/// of the text file the INI names, `load` reads it.
+pub fn import(server_cfg: &str, entry_list: &str) -> anyhow::Result<Config> {
+    let ini: Ini = server_cfg.parse().context("invalid server_cfg.ini")?;
+    let entries: Ini = entry_list.parse().context("invalid entry_list.ini")?;
+    let config = convert(&ini, &entries)?;
+    for key in ini.skipped() {
+        log::warn!("server_cfg.ini {} is not supported, skipped it", key);
+    }
+    for key in entries.skipped() {
+        log::warn!("entry_list.ini {} is not supported, skipped it", key);
+    }
+    Ok(config)
+}
+
+fn convert(ini: &Ini, entries: &Ini) -> anyhow::Result<Config> {
+    let mut config = Config::default();
+
+    let server = ini
+        .section("SERVER")
+        .context("server_cfg.ini has no [SERVER] section")?;
+    import_server(&mut config, server)?;
+
+    let sessions = SESSIONS
+        .iter()
+        .filter_map(|(name, session_type)| Some((ini.section(name)?, *session_type)))
+        .map(|(section, session_type)| import_session(section, session_type))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if !sessions.is_empty() {
+        config.sessions.sessions = sessions;
+    }
+
+    config.dynamictrack.enabled = false;
+    if let Some(track) = ini.section("DYNAMIC_TRACK") {
+        let grip = &mut config.dynamictrack;
+        grip.enabled = true;
+        if let Some(start) = track.get::<f32>("SESSION_START")? {
+            grip.session_start_grip = start / 100.0;
+            grip.base_grip = start / 100.0;
+        }
+        if let Some(randomness) = track.get::<f32>("RANDOMNESS")? {
+            grip.random_grip = randomness / 100.0;
+        }
+        if let Some(transfer) = track.get::<f32>("SESSION_TRANSFER")? {
+            grip.session_transfer = transfer / 100.0;
+        }
+        // laps needed to gain 1% grip
+        if let Some(lap_gain) = track.get::<f32>("LAP_GAIN")? {
+            grip.grip_per_lap = if lap_gain > 0.0 { 0.01 / lap_gain } else { 0.0 };
+        }
+    }
+
+    let weathers = ini
+        .numbered("WEATHER")
+        .map(import_weather)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if !weathers.is_empty() {
+        config.weathers = weathers;
+    }
+
+    config.cars = entries
+        .numbered("CAR")
+        .map(|car| {
+            Ok(Car {
+                model: car
+                    .string("MODEL")
+                    .with_context(|| format!("[{}] has no MODEL", car.name))?,
+                skin: car.string("SKIN").unwrap_or_default(),
+                guid: car.string("GUID"),
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(config)
+}
+
+fn import_server(config: &mut Config, server: &Section) -> anyhow::Result<()> {
+    let options = &mut config.server;
+    if let Some(name) = server.string("NAME") {
+        options.name = name;
+    }
+    options.welcome_message = server.string("WELCOME_MESSAGE").unwrap_or_default();
+    options.tcp_port = server.get("TCP_PORT")?.unwrap_or(options.tcp_port);
+    options.udp_port = server.get("UDP_PORT")?.unwrap_or(options.udp_port);
+    options.http_port = server.get("HTTP_PORT")?.unwrap_or(options.http_port);
+    options.max_clients = server.get("MAX_CLIENTS")?.unwrap_or(options.max_clients);
+    options.client_send_interval_hz = server
+        .get("CLIENT_SEND_INTERVAL_HZ")?
+        .unwrap_or(options.client_send_interval_hz);
+
+    if let Some(track) = server.string("TRACK") {
+        config.track = track;
+    }
+    config.track_config = server.string("CONFIG_TRACK").unwrap_or_default();
+    config.sun_angle = server.get("SUN_ANGLE")?.unwrap_or(config.sun_angle);
+    config.time_of_day_multiplier = server
+        .get("TIME_OF_DAY_MULT")?
+        .unwrap_or(config.time_of_day_multiplier);
+
+    let game = &mut config.game;
+    game.password = server.string("PASSWORD");
+    game.admin_password = server.string("ADMIN_PASSWORD");
+    if let Some(legal_tyres) = server.string("LEGAL_TYRES") {
+        game.legal_tyres = legal_tyres;
+    }
+    game.allowed_tyres = server
+        .get("ALLOWED_TYRES_OUT")?
+        .unwrap_or(game.allowed_tyres);
+    game.tc_allowed = server.get("TC_ALLOWED")?.unwrap_or(game.tc_allowed);
+    game.abs_allowed = server.get("ABS_ALLOWED")?.unwrap_or(game.abs_allowed);
+    game.stability_allowed = server
+        .flag("STABILITY_ALLOWED")?
+        .unwrap_or(game.stability_allowed);
+    game.autoclutch_allowed = server
+        .flag("AUTOCLUTCH_ALLOWED")?
+        .unwrap_or(game.autoclutch_allowed);
+    game.tyre_blankets_allowed = server
+        .flag("TYRE_BLANKETS_ALLOWED")?
+        .unwrap_or(game.tyre_blankets_allowed);
+    game.force_virtual_mirror = server
+        .flag("FORCE_VIRTUAL_MIRROR")?
+        .unwrap_or(game.force_virtual_mirror);
+    game.start_rule = server.get("START_RULE")?.unwrap_or(game.start_rule);
+    // rates are percentages in the INI
+    if let Some(rate) = server.get::<f32>("FUEL_RATE")? {
+        game.fuel_rate = rate / 100.0;
+    }
+    if let Some(rate) = server.get::<f32>("TYRE_WEAR_RATE")? {
+        game.tyre_wear_rate = rate / 100.0;
+    }
+    if let Some(multiplier) = server.get::<f32>("DAMAGE_MULTIPLIER")? {
+        game.damage_multiplier = multiplier / 100.0;
+    }
+    game.max_contacts_per_km = server
+        .get("MAX_CONTACTS_PER_KM")?
+        .unwrap_or(game.max_contacts_per_km);
+    if let Some(seconds) = server.get("VOTE_DURATION")? {
+        game.vote_duration = Duration::from_secs(seconds);
+    }
+    game.voting_quorum = server.get("VOTING_QUORUM")?.unwrap_or(game.voting_quorum);
+    game.kick_quorum = server.get("KICK_QUORUM")?.unwrap_or(game.kick_quorum);
+    game.has_extra_lap = server.flag("RACE_EXTRA_LAP")?.unwrap_or(game.has_extra_lap);
+    game.inverted_grid_positions = server
+        .get("REVERSED_GRID_RACE_POSITIONS")?
+        .unwrap_or(game.inverted_grid_positions);
+    game.pit_window_start = server
+        .get("RACE_PIT_WINDOW_START")?
+        .unwrap_or(game.pit_window_start);
+    game.pit_window_end = server
+        .get("RACE_PIT_WINDOW_END")?
+        .unwrap_or(game.pit_window_end);
+    game.race_gas_penalty_disabled = server
+        .flag("RACE_GAS_PENALTY_DISABLED")?
+        .unwrap_or(game.race_gas_penalty_disabled);
+
+    if let Some(seconds) = server.get("RACE_OVER_TIME")? {
+        config.sessions.race_over_time = Duration::from_secs(seconds);
+    }
+    if let Some(seconds) = server.get("RESULT_SCREEN_TIME")? {
+        config.sessions.result_screen_time = Duration::from_secs(seconds);
+    }
+
+    config.lobby.register_to_lobby = server
+        .flag("REGISTER_TO_LOBBY")?
+        .unwrap_or(config.lobby.register_to_lobby);
+
+    config.plugin = match server.get("UDP_PLUGIN_ADDRESS")? {
+        Some(address) => Some(PluginOptions {
+            address,
+            local_port: server.get("UDP_PLUGIN_LOCAL_PORT")?.unwrap_or_default(),
+        }),
+        None => None,
+    };
+    Ok(())
+}
+
+fn import_session(section: &Section, session_type: u8) -> anyhow::Result<Session> {
+    let minutes: u16 = section.get("TIME")?.unwrap_or_default();
+    Ok(Session {
+        name: section
+            .string("NAME")
+            .unwrap_or_else(|| section.name.clone()),
+        session_type,
+        time: minutes
+            .checked_mul(60)
+            .with_context(|| format!("[{}] TIME is too long", section.name))?,
+        laps: section.get("LAPS")?.unwrap_or_default(),
+        is_open: match section.get::<u8>("IS_OPEN")? {
+            Some(0) => SessionOpenType::Closed,
+            Some(2) => SessionOpenType::WaitOnly,
+            Some(_) => SessionOpenType::Open,
+            // joining a race after the start is rarely wanted
+            None if session_type == 3 => SessionOpenType::WaitOnly,
+            None => SessionOpenType::Open,
+        },
+        wait_time: Duration::from_secs(section.get("WAIT_TIME")?.unwrap_or_default()),
+        weather: None,
+        grip: None,
+    })
+}
+
+fn import_weather(section: &Section) -> anyhow::Result<Weather> {
+    let base_ambient: f32 = section.get("BASE_TEMPERATURE_AMBIENT")?.unwrap_or(20.0);
+    // the road temperature is relative to the ambient one
+    let road_offset: f32 = section.get("BASE_TEMPERATURE_ROAD")?.unwrap_or_default();
+    Ok(Weather {
+        graphics: section
+            .string("GRAPHICS")
+            .with_context(|| format!("[{}] has no GRAPHICS", section.name))?,
+        base_ambient,
+        base_road: base_ambient + road_offset,
+        variation_ambient: section.get("VARIATION_AMBIENT")?.unwrap_or_default(),
+        variation_road: section.get("VARIATION_ROAD")?.unwrap_or_default(),
+        wind: Wind {
+            base_speed_min: section.get("WIND_BASE_SPEED_MIN")?.unwrap_or_default(),
+            base_speed_max: section.get("WIND_BASE_SPEED_MAX")?.unwrap_or_default(),
+            base_direction: section.get("WIND_BASE_DIRECTION")?.unwrap_or_default(),
+            variation_direction: section.get("WIND_VARIATION_DIRECTION")?.unwrap_or_default(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::option::ServerOptions;
+    use std::sync::Arc;
+
+    const SERVER_CFG: &str = "
+[SERVER]
+NAME=Imported
+TRACK=ks_vallelunga
+CONFIG_TRACK=club_circuit
+WELCOME_MESSAGE=cfg/welcome.txt
+TCP_PORT=9601
+UDP_PORT=9601
+HTTP_PORT=8081
+PASSWORD=
+ADMIN_PASSWORD=admin
+FUEL_RATE=200
+VOTE_DURATION=20
+UDP_PLUGIN_ADDRESS=127.0.0.1:11000
+UDP_PLUGIN_LOCAL_PORT=12000
+
+[PRACTICE]
+NAME=Free Practice
+TIME=30
+IS_OPEN=1
+
+[RACE]
+NAME=Race
+LAPS=10
+TIME=0
+WAIT_TIME=60
+
+[DYNAMIC_TRACK]
+SESSION_START=90
+LAP_GAIN=10
+
+[WEATHER_0]
+GRAPHICS=3_clear
+BASE_TEMPERATURE_AMBIENT=18
+BASE_TEMPERATURE_ROAD=6
+";
+
+    const ENTRY_LIST: &str = "
+; comments are ignored
+[CAR_0]
+MODEL=ks_mazda_mx5_cup
+SKIN=00_official
+GUID=76561198000000000
+DRIVERNAME=Driver
+TEAM=
+BALLAST=10
+RESTRICTOR=0
+
+[CAR_1]
+MODEL=ks_mazda_mx5_cup
+SKIN=
+GUID=
+";
+
+    #[test]
+    fn imports_server_cfg_and_entry_list() {
+        let config = import(SERVER_CFG, ENTRY_LIST).unwrap();
+        assert_eq!(config.server.name, "Imported");
+        assert_eq!(config.server.tcp_port, 9601);
+        assert_eq!(config.track, "ks_vallelunga");
+        assert_eq!(config.track_config, "club_circuit");
+        assert_eq!(config.server.welcome_message, "cfg/welcome.txt");
+        assert_eq!(config.game.password, None);
+        assert_eq!(config.game.admin_password.as_deref(), Some("admin"));
+        assert_eq!(config.game.fuel_rate, 2.0);
+        assert_eq!(config.game.vote_duration, Duration::from_secs(20));
+        assert_eq!(config.plugin.as_ref().unwrap().local_port, 12000);
+
+        let sessions: Vec<_> = config
+            .sessions
+            .iter()
+            .map(|s| (s.name.as_str(), s.session_type, s.time, s.laps))
+            .collect();
+        assert_eq!(
+            sessions,
+            vec![("Free Practice", 1, 1800, 0), ("Race", 3, 0, 10)]
+        );
+        assert_eq!(
+            config.sessions.sessions[1].is_open,
+            SessionOpenType::WaitOnly
+        );
+        assert_eq!(
+            config.sessions.sessions[1].wait_time,
+            Duration::from_secs(60)
+        );
+
+        assert!(config.dynamictrack.enabled);
+        assert_eq!(config.dynamictrack.base_grip, 0.9);
+        assert_eq!(config.weathers.len(), 1);
+        assert_eq!(config.weathers[0].base_road, 24.0);
+
+        assert_eq!(config.cars.len(), 2);
+        assert_eq!(config.cars[0].guid.as_deref(), Some("76561198000000000"));
+        assert_eq!(config.cars[1].guid, None);
+
+        let converted: Config = config.to_toml().unwrap().parse().unwrap();
+        assert_eq!(converted.cars.len(), 2);
+        assert_eq!(converted.sessions.len(), 2);
+        assert_eq!(converted.game.vote_duration, Duration::from_secs(20));
+    }
+
+    #[test]
+    fn imports_a_weather_without_variation() {
+        let server_cfg = "
+[SERVER]
+[WEATHER_0]
+GRAPHICS=3_clear
+BASE_TEMPERATURE_AMBIENT=20
+BASE_TEMPERATURE_ROAD=5
+VARIATION_AMBIENT=0
+VARIATION_ROAD=0
+WIND_BASE_SPEED_MIN=0
+WIND_BASE_SPEED_MAX=0
+WIND_BASE_DIRECTION=90
+WIND_VARIATION_DIRECTION=0
+";
+        let config = import(server_cfg, ENTRY_LIST).unwrap();
+        let options = ServerOptions::new(Arc::new(config));
+        let mut options = options.write().unwrap();
+        options.update_weather();
+        let weather = options.current_weather();
+        assert_eq!(weather.ambient.get(), 20.0);
+        assert_eq!(weather.road.get(), 25.0);
+        assert_eq!((weather.wind.speed, weather.wind.direction), (0, 90));
+    }
+
+    #[test]
+    fn lists_the_skipped_keys() {
+        let ini: Ini = SERVER_CFG.parse().unwrap();
+        let entries: Ini = ENTRY_LIST.parse().unwrap();
+        convert(&ini, &entries).unwrap();
+        assert!(ini.skipped().is_empty());
+        assert_eq!(
+            entries.skipped(),
+            vec![
+                "[CAR_0] BALLAST",
+                "[CAR_0] DRIVERNAME",
+                "[CAR_0] RESTRICTOR",
+                "[CAR_0] TEAM"
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_invalid_values() {
+        let error = import("[SERVER]\nTCP_PORT=abc", "").unwrap_err();
+        assert!(error.to_string().contains("TCP_PORT"));
+        let error = import("[SERVER]", "[CAR_0]\nSKIN=red").unwrap_err();
+        assert!(error.to_string().contains("[CAR_0] has no MODEL"));
+    }
+}
@@ -0,0 +1,159 @@
+use crate::client::ClientStatus;
+use crate::config::{UpdateMode, UpdateOptions};
+
+use protocol::packets::{common::Vec3f, server::PositionUpdate};
+
+/// Size of the `MegaPacket` fields written before the position updates.
+const MEGA_PACKET_HEADER_SIZE: usize = 8;
+/// Encoded size of a single `PositionUpdate`.
+const POSITION_UPDATE_SIZE: usize = 55;
+
+#[derive(Debug, Clone)]
+pub struct TrackPosition {
+    pub pos: Vec3f,
+    pub normalized_pos: f32,
+}
+
+impl From<&ClientStatus> for TrackPosition {
+    fn from(status: &ClientStatus) -> Self {
+        Self {
+            pos: status.pos.clone(),
+            normalized_pos: status.normalized_pos,
+        }
+    }
+}
+
+impl TrackPosition {
+    pub fn distance(&self, other: &TrackPosition) -> f32 {
+        let x = self.pos.x - other.pos.x;
+        let y = self.pos.y - other.pos.y;
+        let z = self.pos.z - other.pos.z;
+        (x * x + y * y + z * z).sqrt()
+    }
+
+    /// Gap along the track spline as a fraction of a lap, across the finish line too.
+    pub fn spline_gap(&self, other: &TrackPosition) -> f32 {
+        let gap = (self.normalized_pos - other.normalized_pos).abs().fract();
+        gap.min(1.0 - gap)
+    }
+}
+
+pub fn is_near(options: &UpdateOptions, receiver: &TrackPosition, other: &TrackPosition) -> bool {
+    match options.mode {
+        UpdateMode::Distance => receiver.distance(other) <= options.near_distance,
+        UpdateMode::Spline => receiver.spline_gap(other) <= options.near_spline_gap,
+    }
+}
+
+/// Near cars are sent every tick, distant ones every `far_update_interval` ticks.
+/// Distant cars are staggered by id so they don't all land on the same tick.
+pub fn should_send(options: &UpdateOptions, tick: u64, car_id: usize, near: bool) -> bool {
+    let interval = u64::from(options.far_update_interval.max(1));
+    near || (tick + car_id as u64).is_multiple_of(interval)
+}
+
+/// Splits updates so that every `MegaPacket` stays under `max_packet_size` bytes.
+pub fn split_updates(
+    updates: Vec<PositionUpdate>,
+    max_packet_size: usize,
+) -> Vec<Vec<PositionUpdate>> {
+    let per_packet = (max_packet_size.saturating_sub(MEGA_PACKET_HEADER_SIZE)
+        / POSITION_UPDATE_SIZE)
+        .clamp(1, u8::MAX as usize);
+    updates.chunks(per_packet).map(<[_]>::to_vec).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::io::Writeable;
+    use protocol::packets::server::MegaPacket;
+
+    fn options(mode: UpdateMode) -> UpdateOptions {
+        UpdateOptions {
+            mode,
+            near_distance: 100.0,
+            near_spline_gap: 0.05,
+            far_update_interval: 4,
+            max_packet_size: 1200,
+        }
+    }
+
+    fn position(x: f32, normalized_pos: f32) -> TrackPosition {
+        TrackPosition {
+            pos: Vec3f { x, y: 0.0, z: 0.0 },
+            normalized_pos,
+        }
+    }
+
+    fn update(car_id: u8) -> PositionUpdate {
+        PositionUpdate {
+            car_id,
+            pak_sequence_id: 0,
+            timestamp: 0,
+            pos: Vec3f::default(),
+            rotation: Vec3f::default(),
+            velocity: Vec3f::default(),
+            tyre_angular_speed: 0,
+            tyre_angular_speed1: 0,
+            tyre_angular_speed2: 0,
+            tyre_angular_speed3: 0,
+            streer_angle: 0,
+            wheel_angle: 0,
+            engine_rpm: 0,
+            gear: 0,
+            status: 0,
+        }
+    }
+
+    #[test]
+    fn spline_gap_wraps_around_finish_line() {
+        let options = options(UpdateMode::Spline);
+        assert!(is_near(
+            &options,
+            &position(0.0, 0.99),
+            &position(0.0, 0.01)
+        ));
+        assert!(!is_near(&options, &position(0.0, 0.5), &position(0.0, 0.6)));
+    }
+
+    #[test]
+    fn distant_cars_are_staggered() {
+        let options = options(UpdateMode::Distance);
+        assert!(!is_near(
+            &options,
+            &position(0.0, 0.0),
+            &position(500.0, 0.0)
+        ));
+        let sent = (0..8)
+            .filter(|tick| should_send(&options, *tick, 3, false))
+            .count();
+        assert_eq!(sent, 2);
+        assert!((0..8).all(|tick| should_send(&options, tick, 3, true)));
+    }
+
+    #[test]
+    fn split_updates_fit_packet_size() {
+        let updates: Vec<PositionUpdate> = (0..40).map(update).collect();
+        let packets = split_updates(updates, 1200);
+        assert_eq!(packets.iter().map(Vec::len).sum::<usize>(), 40);
+        for position_updates in packets {
+            let mut buffer = Vec::new();
+            MegaPacket {
+                timestamp: 0,
+                ping: 0,
+                position_updates,
+            }
+            .write(&mut buffer)
+            .unwrap();
+            assert!(buffer.len() <= 1200);
+        }
+    }
+
+    #[test]
+    fn position_update_size() {
+        let mut buffer = Vec::new();
+        update(0).write(&mut buffer).unwrap();
+        assert_eq!(buffer.len(), POSITION_UPDATE_SIZE);
+    }
+}
@@ -0,0 +1,36 @@
+#![feature(cell_update)]
+pub mod admin;
+pub mod builder;
+pub mod car;
+pub mod chat;
+pub mod client;
+pub mod config;
+pub mod dynamictrack;
+pub mod event;
+pub mod http;
+pub mod ini;
+pub mod interest;
+pub mod listener;
+pub mod live;
+pub mod lobby;
+pub mod metrics;
+pub mod option;
+pub mod penalty;
+pub mod plugin;
+pub mod readwrite;
+pub mod reload;
+pub mod results;
+pub mod server;
+pub mod session;
+pub mod system;
+pub mod tickloop;
+pub mod udpserver;
+pub mod validation;
+pub mod vote;
+pub mod weather;
+
+pub use builder::{BoundAddresses, ServerBuilder, ServerHandle};
+pub use event::ServerEvent;
+
+use crate::car::Cars;
+use crate::option::ServerOptions;
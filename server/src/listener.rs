@@ -1,6 +1,7 @@
 use crate::{
     car::{Cars, Driver},
     config::Config,
+    metrics::Metrics,
     option::ServerOptions,
     readwrite::{Reader, Writer},
     server::NewPlayer,
@@ -9,17 +10,16 @@ use crate::{
 use anyhow::{bail, Context};
 use flume::{Receiver, Sender};
 use futures_lite::FutureExt;
-use protocol::{
-    io::{Readable, Writeable},
-    packets::{
-        client::{JoinRequest, TestClient},
-        common::PROTOCOL_VERSION,
-        server::{NewCarConnection, NoSlotsForCarModel, TestServer, WrongPassword, WrongProtocol},
+use protocol::packets::{
+    client::{JoinRequest, TestClient},
+    common::PROTOCOL_VERSION,
+    server::{
+        Banned, NewCarConnection, NoSlotsForCarModel, SessionClosed, TestServer, WrongPassword,
+        WrongProtocol,
     },
 };
 
 use std::{
-    fmt::Debug,
     net::{IpAddr, SocketAddr},
     sync::{Arc, RwLock},
     time::Instant,
@@ -30,64 +30,54 @@ use tokio::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
         TcpListener, TcpStream,
     },
+    sync::{mpsc, Notify},
+    task::AbortHandle,
 };
+/// What every connection needs from the server, cloned for each of them.
+#[derive(Clone)]
+pub struct ConnectionHandles {
+    pub start_time: Instant,
+    pub config: Arc<Config>,
+    pub options: Arc<RwLock<ServerOptions>>,
+    pub cars: Arc<Cars>,
+    pub new_players: Sender<NewPlayer>,
+    pub udp_packets_to_send: Sender<UdpServerMessage>,
+    pub tcp_activity: Arc<Notify>,
+    /// Never sent on, dropped once everything queued for the clients is written.
+    pub shutdown_complete: mpsc::Sender<()>,
+    pub metrics: Arc<Metrics>,
+}
+
 pub struct Listener {
-    start_time: Instant,
     listener: TcpListener,
-    config: Arc<Config>,
-    options: Arc<RwLock<ServerOptions>>,
-    cars: Arc<Cars>,
-    new_players: Sender<NewPlayer>,
-    udp_packets_to_send: Sender<UdpServerMessage>,
+    handles: ConnectionHandles,
 }
 
 impl Listener {
-    pub async fn start(
-        start_time: Instant,
-        config: Arc<Config>,
-        options: Arc<RwLock<ServerOptions>>,
-        cars: Arc<Cars>,
-        new_players: Sender<NewPlayer>,
-        udp_packets_to_send: Sender<UdpServerMessage>,
-    ) -> anyhow::Result<()> {
+    pub async fn start(handles: ConnectionHandles) -> anyhow::Result<(SocketAddr, AbortHandle)> {
+        let config = &handles.config;
         let address = format!("{}:{}", config.server.address, config.server.tcp_port);
         let listener = TcpListener::bind(&address)
             .await
             .context("failed to bind to port - maybe a server is already running?")?;
-        let listener = Listener {
-            start_time,
-            listener,
-            config,
-            options,
-            cars,
-            new_players,
-            udp_packets_to_send,
-        };
+        let local_addr = listener.local_addr()?;
+        let listener = Listener { listener, handles };
 
-        tokio::spawn(async move {
+        let task = tokio::spawn(async move {
             listener.run().await;
         });
-        Ok(())
+        Ok((local_addr, task.abort_handle()))
     }
     async fn run(mut self) {
         loop {
             if let Ok((stream, addr)) = self.listener.accept().await {
                 log::debug!("Accepting Connection: {}", addr);
-                self.accept(stream, addr).await;
+                self.accept(stream).await;
             }
         }
     }
-    async fn accept(&mut self, stream: TcpStream, addr: SocketAddr) {
-        let worker = Worker::new(
-            stream,
-            addr,
-            self.config.clone(),
-            self.options.clone(),
-            self.cars.clone(),
-            self.new_players.clone(),
-            self.udp_packets_to_send.clone(),
-            self.start_time.clone(),
-        );
+    async fn accept(&mut self, stream: TcpStream) {
+        let worker = Worker::new(stream, self.handles.clone());
         worker.start();
     }
 }
@@ -104,25 +94,36 @@ pub struct Worker {
     options: Arc<RwLock<ServerOptions>>,
     udp_packets_to_send: Sender<UdpServerMessage>,
     start_time: Instant,
+    tcp_activity: Arc<Notify>,
+    /// Never sent on, dropped once everything queued for the client is written.
+    shutdown_complete: mpsc::Sender<()>,
+    metrics: Arc<Metrics>,
 }
 impl Worker {
-    pub fn new(
-        stream: TcpStream,
-        _addr: SocketAddr,
-        config: Arc<Config>,
-        options: Arc<RwLock<ServerOptions>>,
-        cars: Arc<Cars>,
-        new_players: Sender<NewPlayer>,
-        udp_packets_to_send: Sender<UdpServerMessage>,
-        start_time: Instant,
-    ) -> Self {
+    pub fn new(stream: TcpStream, handles: ConnectionHandles) -> Self {
+        let ConnectionHandles {
+            start_time,
+            config,
+            options,
+            cars,
+            new_players,
+            udp_packets_to_send,
+            tcp_activity,
+            shutdown_complete,
+            metrics,
+        } = handles;
         let ip = stream.peer_addr().unwrap().ip();
         let (reader, writer) = stream.into_split();
 
         let (received_packets_tx, received_packets_rx) = flume::bounded(32);
         let (packets_to_send_tx, packets_to_send_rx) = flume::unbounded();
-        let reader = Reader::new(reader, received_packets_tx);
-        let writer = Writer::new(writer, packets_to_send_rx);
+        let reader = Reader::new(
+            reader,
+            received_packets_tx,
+            tcp_activity.clone(),
+            metrics.clone(),
+        );
+        let writer = Writer::new(writer, packets_to_send_rx, metrics.clone());
 
         Self {
             reader,
@@ -136,6 +137,9 @@ impl Worker {
             new_players,
             udp_packets_to_send,
             start_time,
+            tcp_activity,
+            shutdown_complete,
+            metrics,
         }
     }
 
@@ -159,13 +163,29 @@ impl Worker {
             bail!("Unexpected Protocol")
         }
 
+        let (admin_password, password, banned, joinable) = {
+            let options = self.options.read().unwrap();
+            (
+                options.admin_password.clone(),
+                options.password.clone(),
+                options.is_banned(&joiner.guid),
+                options.sessions.is_joinable(),
+            )
+        };
+        // a returning driver gets their car back whatever the session allows
+        let joinable = joinable || self.cars.is_held_for(&joiner.guid);
+        if banned {
+            self.write(TestServer::Banned(Banned {})).await?;
+            bail!("Banned")
+        }
+
         let mut admin = false;
-        if let Some(admin_password) = &self.config.game.admin_password {
+        if let Some(admin_password) = &admin_password {
             admin = joiner.server_password == *admin_password;
         }
 
         if !admin {
-            if let Some(password) = &self.config.game.password {
+            if let Some(password) = &password {
                 if joiner.server_password != *password {
                     self.write(TestServer::WrongPassword(WrongPassword {}))
                         .await?;
@@ -174,6 +194,13 @@ impl Worker {
             }
         }
 
+        // admins can always join, e.g. to fix a race in progress
+        if !joinable && !admin {
+            self.write(TestServer::SessionClosed(SessionClosed {}))
+                .await?;
+            bail!("Session closed")
+        }
+
         let driver = Driver {
             name: joiner.driver_name,
             team: "".into(),
@@ -209,7 +236,7 @@ impl Worker {
                 race_gas_penalty_disabled: self.config.game.race_gas_penalty_disabled,
                 pit_window_start: self.config.game.pit_window_start,
                 pit_window_end: self.config.game.pit_window_end,
-                inverted_grid_positions: 4, //todo
+                inverted_grid_positions: self.config.game.inverted_grid_positions,
                 session_id: index as u8,
                 sessions: options.sessions.clone().into(),
                 session_name: options.sessions.get_current_session().name.clone(),
@@ -236,22 +263,31 @@ impl Worker {
     }
 
     async fn run(mut self) -> anyhow::Result<()> {
-        match self.read::<TestClient>().await? {
+        match self.read().await? {
             TestClient::JoinRequest(joiner) => {
                 log::debug!("Sending JoinRequest");
 
                 if let Ok((id, admin, guid)) = self.handle_joinrequest(joiner).await {
+                    let received_packets = self.received_packets();
+                    let packets_to_send = self.packets_to_send();
+                    let udp_packets_to_send = self.udp_packets_to_send();
+                    let new_players = self.new_players.clone();
+                    let ip = self.ip;
+                    let metrics = self.metrics.clone();
+                    let tcp_reader = self.split(id);
+
                     let new_player = NewPlayer {
-                        received_packets: self.received_packets(),
-                        packets_to_send: self.packets_to_send(),
+                        received_packets,
+                        packets_to_send,
                         car_id: id,
-                        ip: self.ip,
+                        ip,
                         booked_as_admin: admin,
                         guid,
-                        udp_packets_to_send: self.udp_packets_to_send(),
+                        udp_packets_to_send,
+                        tcp_reader,
+                        metrics,
                     };
-                    let _ = self.new_players.send_async(new_player).await;
-                    self.split(id);
+                    let _ = new_players.send_async(new_player).await;
                 }
             }
             _ => bail!("Unexpected packet"),
@@ -259,33 +295,43 @@ impl Worker {
         Ok(())
     }
 
-    pub async fn read<P: Readable>(&mut self) -> anyhow::Result<P> {
+    pub async fn read(&mut self) -> anyhow::Result<TestClient> {
         self.reader.read().await
     }
 
-    pub async fn write(&mut self, packet: impl Writeable + Debug) -> anyhow::Result<()> {
+    pub async fn write(&mut self, packet: TestServer) -> anyhow::Result<()> {
         self.writer.write(packet).await
     }
 
-    pub fn split(self, id: usize) {
+    /// Spawns the reader and writer tasks. The server tears the client down
+    /// once either of them stops; the returned handle stops the reader.
+    /// The writer stops by itself after flushing once the client is dropped.
+    pub fn split(self, id: usize) -> AbortHandle {
         let Self {
             reader,
             writer,
-            cars,
+            tcp_activity,
+            shutdown_complete,
             ..
         } = self;
         let reader = tokio::task::spawn(async move { reader.run().await });
-        let writer = tokio::task::spawn(async move { writer.run().await });
+        let writer = tokio::task::spawn(async move {
+            let result = writer.run().await;
+            drop(shutdown_complete);
+            result
+        });
+        let tcp_reader = reader.abort_handle();
 
         tokio::task::spawn(async move {
-            let result = reader.race(writer).await.expect("task panicked");
-            if let Err(e) = result {
-                //let message = disconnected_message(e);
-                //self.cars.remove_car(id);
-                log::debug!("{} lost connection: {}", id, e);
-                cars.remove_car(id);
+            match reader.race(writer).await {
+                Ok(Err(e)) => log::debug!("{} lost connection: {}", id, e),
+                Err(e) if e.is_panic() => log::error!("{} connection task panicked", id),
+                _ => {}
             }
+            // wake the server so it tears the client down right away
+            tcp_activity.notify_one();
         });
+        tcp_reader
     }
 
     pub fn packets_to_send(&self) -> Sender<TestServer> {
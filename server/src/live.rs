@@ -0,0 +1,278 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::TryRecvError};
+
+use crate::admin::{self, Standing};
+use crate::event::ServerEvent;
+use crate::server::Server;
+use crate::system::{SysResult, SystemExecutor};
+
+/// How many events a slow `/live` client can fall behind before it misses some.
+const LIVE_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CarPosition {
+    pub car_id: usize,
+    /// Distance along the track, 0.0 to 1.0.
+    pub normalized_pos: f32,
+    /// World position, enough to draw a track map.
+    pub x: f32,
+    pub z: f32,
+    pub speed_kmh: f32,
+    pub gear: u8,
+}
+
+/// What `/live` pushes to overlays.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LiveEvent {
+    Positions {
+        cars: Vec<CarPosition>,
+    },
+    Lap {
+        car_id: usize,
+        laptime: u32,
+        cuts: u8,
+        laps: u32,
+    },
+    Sector {
+        car_id: usize,
+        sector: u8,
+        time: u32,
+        cuts: u8,
+    },
+    Standings {
+        standings: Vec<Standing>,
+    },
+    Session {
+        index: usize,
+        name: String,
+        session_type: u8,
+    },
+    Chat {
+        car_id: usize,
+        message: String,
+    },
+}
+
+impl LiveEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            LiveEvent::Positions { .. } => "positions",
+            LiveEvent::Lap { .. } => "lap",
+            LiveEvent::Sector { .. } => "sector",
+            LiveEvent::Standings { .. } => "standings",
+            LiveEvent::Session { .. } => "session",
+            LiveEvent::Chat { .. } => "chat",
+        }
+    }
+
+    /// The event as a server-sent event, `data` is the JSON of the event.
+    pub fn to_sse(&self) -> String {
+        let data = serde_json::to_string(self).expect("live events always serialize");
+        format!("event: {}\ndata: {}\n\n", self.name(), data)
+    }
+
+    fn from_server_event(event: ServerEvent) -> Option<LiveEvent> {
+        Some(match event {
+            ServerEvent::LapCompleted {
+                car_id,
+                laptime,
+                cuts,
+                laps,
+            } => LiveEvent::Lap {
+                car_id,
+                laptime,
+                cuts,
+                laps,
+            },
+            ServerEvent::SectorSplit {
+                car_id,
+                sector,
+                time,
+                cuts,
+            } => LiveEvent::Sector {
+                car_id,
+                sector,
+                time,
+                cuts,
+            },
+            ServerEvent::SessionChanged {
+                index,
+                name,
+                session_type,
+            } => LiveEvent::Session {
+                index,
+                name,
+                session_type,
+            },
+            ServerEvent::Chat { car_id, message } => LiveEvent::Chat { car_id, message },
+            _ => return None,
+        })
+    }
+
+    /// Standings and the session stay true until replaced, so late subscribers get them too.
+    fn is_retained(&self) -> bool {
+        matches!(
+            self,
+            LiveEvent::Standings { .. } | LiveEvent::Session { .. }
+        )
+    }
+}
+
+/// Sends live timing to every `/live` client as ready made server-sent events.
+#[derive(Debug, Clone)]
+pub struct LiveFeed {
+    sender: broadcast::Sender<String>,
+    retained: Arc<Mutex<Vec<(&'static str, String)>>>,
+}
+
+impl Default for LiveFeed {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(LIVE_CAPACITY);
+        Self {
+            sender,
+            retained: Default::default(),
+        }
+    }
+}
+
+impl LiveFeed {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn publish(&self, event: &LiveEvent) {
+        let sse = event.to_sse();
+        if event.is_retained() {
+            let mut retained = self.retained.lock().unwrap();
+            retained.retain(|(name, _)| *name != event.name());
+            retained.push((event.name(), sse.clone()));
+        }
+        // nobody watching is fine
+        let _ = self.sender.send(sse);
+    }
+
+    /// Subscribes to the feed, starting with the current session and standings.
+    pub fn subscribe(&self) -> (Vec<String>, broadcast::Receiver<String>) {
+        let receiver = self.sender.subscribe();
+        let retained = self.retained.lock().unwrap();
+        let current = retained.iter().map(|(_, sse)| sse.clone()).collect();
+        (current, receiver)
+    }
+
+    fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+}
+
+pub fn register(
+    systems: &mut SystemExecutor<Server>,
+    feed: LiveFeed,
+    mut events: broadcast::Receiver<ServerEvent>,
+) {
+    let mut last_update: Option<Instant> = None;
+    let mut standings = Vec::new();
+    systems.add_system("live timing", move |server| {
+        update_live(server, &feed, &mut events, &mut last_update, &mut standings)
+    });
+}
+
+/// Publishes laps, sectors, chat and session changes as they come, and car positions and
+/// changed standings every `live_timing.interval`.
+fn update_live(
+    server: &mut Server,
+    feed: &LiveFeed,
+    events: &mut broadcast::Receiver<ServerEvent>,
+    last_update: &mut Option<Instant>,
+    standings: &mut Vec<Standing>,
+) -> SysResult {
+    if last_update.is_none() {
+        let options = server.options.read().unwrap();
+        let session = options.sessions.get_current_session();
+        feed.publish(&LiveEvent::Session {
+            index: options.sessions.get_current(),
+            name: session.name.clone(),
+            session_type: session.session_type.clone() as u8,
+        });
+    }
+    loop {
+        match events.try_recv() {
+            Ok(event) => {
+                if let Some(event) = LiveEvent::from_server_event(event) {
+                    feed.publish(&event);
+                }
+            }
+            Err(TryRecvError::Lagged(missed)) => {
+                log::warn!("Live timing missed {} events", missed)
+            }
+            Err(_) => break,
+        }
+    }
+
+    if last_update.is_some_and(|last| last.elapsed() < server.config.live_timing.interval) {
+        return Ok(());
+    }
+    *last_update = Some(Instant::now());
+
+    let current = admin::standings(server);
+    if current != *standings {
+        *standings = current;
+        feed.publish(&LiveEvent::Standings {
+            standings: standings.clone(),
+        });
+    }
+    if feed.has_subscribers() {
+        let cars = server
+            .clients
+            .iter()
+            .map(|client| {
+                let status = client.status();
+                let v = &status.velocity;
+                CarPosition {
+                    car_id: client.car_id,
+                    normalized_pos: status.normalized_pos,
+                    x: status.pos.x,
+                    z: status.pos.z,
+                    speed_kmh: (v.x * v.x + v.y * v.y + v.z * v.z).sqrt() * 3.6,
+                    gear: status.gear,
+                }
+            })
+            .collect();
+        feed.publish(&LiveEvent::Positions { cars });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn late_subscribers_get_the_current_session() {
+        let feed = LiveFeed::new();
+        let session = |index| LiveEvent::Session {
+            index,
+            name: "Race".into(),
+            session_type: 3,
+        };
+        feed.publish(&session(0));
+        feed.publish(&session(1));
+
+        let (current, mut receiver) = feed.subscribe();
+        assert_eq!(current, vec![session(1).to_sse()]);
+        assert!(current[0].starts_with("event: session\ndata: {\"type\":\"session\",\"index\":1,"));
+
+        let lap = LiveEvent::Lap {
+            car_id: 0,
+            laptime: 90000,
+            cuts: 0,
+            laps: 1,
+        };
+        feed.publish(&lap);
+        assert_eq!(receiver.try_recv().unwrap(), lap.to_sse());
+        assert_eq!(feed.subscribe().0.len(), 1);
+    }
+}
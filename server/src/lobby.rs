@@ -0,0 +1,200 @@
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use hyper::client::HttpConnector;
+use hyper::{body, Client, Uri};
+use protocol::json::Info;
+use protocol::packets::common::PROTOCOL_VERSION;
+
+use crate::car::Cars;
+use crate::config::Config;
+use crate::http::HttpServer;
+use crate::option::ServerOptions;
+
+/// How long the lobby gets to answer a call.
+const LOBBY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Registers the server to the lobby when `lobby.register_to_lobby` is set and keeps
+/// pinging it, registering again whenever the lobby forgets the server.
+pub fn start(
+    config: Arc<Config>,
+    options: Arc<RwLock<ServerOptions>>,
+    cars: Arc<Cars>,
+    start_time: Instant,
+) {
+    if !config.lobby.register_to_lobby {
+        return;
+    }
+    tokio::spawn(async move {
+        let client = Client::new();
+        let mut interval = tokio::time::interval(config.lobby.ping_interval);
+        let mut registered = false;
+        loop {
+            interval.tick().await;
+            let info = HttpServer::info(&config, &options, &cars, start_time);
+            let result = match registered {
+                true => call(&client, &config, "ping", ping_query(&info)).await,
+                false => call(&client, &config, "register", register_query(&config, &info)).await,
+            };
+            match result {
+                Ok(()) if !registered => {
+                    log::info!("Registered to lobby {}", config.lobby.url);
+                    registered = true;
+                }
+                Ok(()) => {}
+                Err(e) => {
+                    log::warn!("{:?}", e);
+                    registered = false;
+                }
+            }
+        }
+    });
+}
+
+/// Calls `endpoint` of the lobby, which answers `OK` or an error message.
+async fn call(
+    client: &Client<HttpConnector>,
+    config: &Config,
+    endpoint: &str,
+    query: String,
+) -> anyhow::Result<()> {
+    let url = format!(
+        "{}/{}?{}",
+        config.lobby.url.trim_end_matches('/'),
+        endpoint,
+        query
+    );
+    let uri: Uri = url
+        .parse()
+        .with_context(|| format!("invalid lobby url {}", url))?;
+    let response = tokio::time::timeout(LOBBY_TIMEOUT, client.get(uri))
+        .await
+        .with_context(|| format!("lobby {} timed out", endpoint))?
+        .with_context(|| format!("lobby {} failed", endpoint))?;
+    let body = body::to_bytes(response.into_body()).await?;
+    let body = String::from_utf8_lossy(&body);
+    if !body.starts_with("OK") {
+        bail!("lobby {} refused: {}", endpoint, body.trim());
+    }
+    Ok(())
+}
+
+fn register_query(config: &Config, info: &Info) -> String {
+    let mut models: Vec<&str> = Vec::new();
+    for model in info.cars.iter() {
+        if !models.contains(&model.as_str()) {
+            models.push(model);
+        }
+    }
+    let game = &config.game;
+    query(&[
+        ("name", info.name.clone()),
+        // the lobby reads /INFO from this port
+        ("port", info.cport.to_string()),
+        ("tcp_port", info.tport.to_string()),
+        ("max_clients", info.maxclients.to_string()),
+        ("track", info.track.clone()),
+        ("cars", models.join(",")),
+        ("timeofday", info.timeofday.to_string()),
+        ("sessions", join(&info.sessiontypes)),
+        ("durations", join(&info.durations)),
+        ("password", flag(info.pass)),
+        ("version", PROTOCOL_VERSION.to_string()),
+        ("pickup", flag(info.pickup)),
+        ("autoclutch", flag(game.autoclutch_allowed)),
+        ("abs", game.abs_allowed.to_string()),
+        ("tc", game.tc_allowed.to_string()),
+        ("stability", flag(game.stability_allowed)),
+        ("legal_tyres", game.legal_tyres.clone()),
+        ("fixed_setup", flag(false)),
+        ("timed", flag(info.timed)),
+        ("extra", flag(info.extra)),
+        ("pit", flag(info.pit)),
+        ("inverted", info.inverted.to_string()),
+    ])
+}
+
+fn ping_query(info: &Info) -> String {
+    let session_type = info
+        .sessiontypes
+        .get(info.session as usize)
+        .copied()
+        .unwrap_or_default();
+    query(&[
+        ("session", session_type.to_string()),
+        ("timeleft", info.timeleft.to_string()),
+        ("port", info.cport.to_string()),
+        ("clients", info.clients.to_string()),
+        ("track", info.track.clone()),
+        ("pickup", flag(info.pickup)),
+    ])
+}
+
+fn query(params: &[(&str, String)]) -> String {
+    params
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, urlencoding::encode(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn join<T: ToString>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn flag(value: bool) -> String {
+    u8::from(value).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn registers_then_pings_the_lobby() {
+        let (requests_tx, mut requests) = mpsc::unbounded_channel();
+        let lobby =
+            Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(move |_| {
+                let requests_tx = requests_tx.clone();
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                        let _ = requests_tx.send(req.uri().clone());
+                        async { Ok::<_, hyper::Error>(Response::new(Body::from("OK"))) }
+                    }))
+                }
+            }));
+        let lobby_addr = lobby.local_addr();
+        tokio::spawn(lobby);
+
+        let mut config = Config::default();
+        config.lobby.register_to_lobby = true;
+        config.lobby.url = format!("http://{}/lobby.ashx/", lobby_addr);
+        config.lobby.ping_interval = Duration::from_millis(10);
+        let config = Arc::new(config);
+        start(
+            config.clone(),
+            ServerOptions::new(config.clone()),
+            Arc::new(Cars::new(config)),
+            Instant::now(),
+        );
+
+        let register = requests.recv().await.unwrap();
+        assert_eq!(register.path(), "/lobby.ashx/register");
+        let query = register.query().unwrap();
+        assert!(query.starts_with("name=Test%20Assetto%20Server&port=8080&tcp_port=9600"));
+        assert!(query.contains("&cars=ks_mercedes_190_evo2&"));
+        assert!(query.contains("&password=0&version=202&"));
+
+        let ping = requests.recv().await.unwrap();
+        assert_eq!(ping.path(), "/lobby.ashx/ping");
+        assert!(ping.query().unwrap().contains("&clients=0&"));
+    }
+}
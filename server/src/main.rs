@@ -1,77 +1,180 @@
-#![feature(cell_update)]
-pub mod car;
-pub mod client;
-pub mod config;
-pub mod dynamictrack;
-pub mod http;
-pub mod listener;
-pub mod option;
-pub mod readwrite;
-pub mod server;
-pub mod session;
-pub mod system;
-pub mod tickloop;
-pub mod udpserver;
-pub mod weather;
-/*main.DynamicTrack.Enabled = false;
-main.DynamicTrack.SessionStartGrip = 0.8;
-main.DynamicTrack.BaseGrip = 0.8;
-main.DynamicTrack.GripPerLap = 0.1;
-main.DynamicTrack.RandomGrip = 0.0;*/
-
-use crate::http::HttpServer;
-use crate::udpserver::UdpServer;
-use std::borrow::BorrowMut;
-
-/*  ks.GetTimeMillis(puVar4,pvVar5);
-local_144 = (*(int *)&main.CurrentSession->Time * 60000 -
-            (int)(puVar4 + -*(int *)&main.CurrentSession->StartTime)) / 1000;*/
-use crate::option::ServerOptions;
-use crate::{server::Server, tickloop::TickLoop};
-use anyhow::Context;
-use config::Config;
+use std::fs;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use anyhow::{bail, Context};
+use argh::FromArgs;
+use server::config::{Config, DEFAULT_CONFIG};
+use server::{ini, ServerBuilder};
 
 const CONFIG_PATH: &str = "config.toml";
-use crate::car::Cars;
 
-use std::sync::Arc;
-pub struct Game {}
+/// An Assetto Corsa server.
+#[derive(Debug, FromArgs)]
+struct Args {
+    /// the config to run, config.toml by default.
+    #[argh(option, short = 'c', default = "PathBuf::from(CONFIG_PATH)")]
+    config: PathBuf,
+    /// read server_cfg.ini and entry_list.ini from this directory instead of the config.
+    #[argh(option)]
+    ini: Option<PathBuf>,
+    /// the game's content directory, overrides server.content_dir.
+    #[argh(option)]
+    content: Option<PathBuf>,
+    /// overrides server.tcp_port.
+    #[argh(option)]
+    tcp_port: Option<u16>,
+    /// overrides server.udp_port.
+    #[argh(option)]
+    udp_port: Option<u16>,
+    /// overrides server.http_port.
+    #[argh(option)]
+    http_port: Option<u16>,
+    /// overrides log.level: trace, debug, info, warn or error.
+    #[argh(option)]
+    log_level: Option<log::LevelFilter>,
+    /// print every problem found in the config and content, then exit.
+    #[argh(switch)]
+    check: bool,
+    /// print the default config.toml and exit.
+    #[argh(switch)]
+    print_default_config: bool,
+    #[argh(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    ConvertIni(ConvertIni),
+}
+
+/// Write the config.toml equivalent of a server_cfg.ini and entry_list.ini and exit.
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "convert-ini")]
+struct ConvertIni {
+    /// directory containing server_cfg.ini and entry_list.ini.
+    #[argh(positional)]
+    dir: PathBuf,
+    /// where to write the config, config.toml by default.
+    #[argh(option, short = 'o', default = "PathBuf::from(CONFIG_PATH)")]
+    output: PathBuf,
+}
+
+/// Loads the config selected by `args` and applies the overrides.
+fn load_config(args: &Args) -> anyhow::Result<Config> {
+    let mut config = match &args.ini {
+        Some(dir) => ini::load(dir)?,
+        None => Config::load(&args.config)?,
+    };
+    if let Some(content) = &args.content {
+        config.server.content_dir = Some(content.clone());
+    }
+    if let Some(port) = args.tcp_port {
+        config.server.tcp_port = port;
+    }
+    if let Some(port) = args.udp_port {
+        config.server.udp_port = port;
+    }
+    if let Some(port) = args.http_port {
+        config.server.http_port = port;
+    }
+    if let Some(level) = args.log_level {
+        config.log.level = level;
+    }
+    Ok(config)
+}
+
+fn convert_ini(command: &ConvertIni) -> anyhow::Result<()> {
+    if command.output.exists() {
+        bail!("{} already exists", command.output.display());
+    }
+    let config = ini::load(&command.dir)?;
+    fs::write(&command.output, config.to_toml()?)?;
+    println!("Wrote {}", command.output.display());
+    Ok(())
+}
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> ExitCode {
+    match run(argh::from_env()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            // one line with the causes, not the backtrace `Result` from main prints
+            eprintln!("Error: {:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(args: Args) -> anyhow::Result<()> {
+    if let Some(Command::ConvertIni(command)) = &args.command {
+        return convert_ini(command);
+    }
+    if args.print_default_config {
+        print!("{}", DEFAULT_CONFIG);
+        return Ok(());
+    }
+
     log::debug!("Loading configuration");
-    let config = Config::load(CONFIG_PATH).context("failed to load configuration file")?;
+    let config = load_config(&args).context("failed to load configuration file")?;
+    if args.check {
+        let report = config.report();
+        print!("{}", report);
+        if report.has_errors() {
+            bail!("config is invalid");
+        }
+        println!("Config is valid");
+        return Ok(());
+    }
     log::debug!("{:?}", config);
     env_logger::builder().filter_level(config.log.level).init();
 
-    let config = Arc::new(config);
-    let cars = Arc::new(Cars::new(Arc::clone(&config)));
-    let mut options = ServerOptions::new(Arc::clone(&config));
-    options.borrow_mut().write().unwrap().update_weather();
-    let udp_server = UdpServer::bind(Arc::clone(&config)).await?;
-
-    let mut server = Server::bind(
-        config.clone(),
-        cars.clone(),
-        options.clone(),
-        udp_server.received_packets(),
-        udp_server.packets_to_send(),
-    )
-    .await?;
-
-    let http_server = HttpServer::serve(config.clone(), options.clone(), cars.clone()).await?;
-
-    let tickloop = TickLoop::new(config.server.client_send_interval_hz.into(), move || {
-        udp_server.listen();
-        udp_server.send_udp();
-        server.accept_new_players();
-        server.handle_udp_messages();
-        server.handle_tcp_packets();
-        server.send_pings_and_updates();
-
-        //log::debug!("tick");
-        false
+    let mut server = ServerBuilder::new(config).start().await?;
+    let reloads = reload_requests();
+    loop {
+        tokio::select! {
+            result = server.wait() => return result,
+            _ = shutdown_signal() => break,
+            Ok(()) = reloads.recv_async() => {
+                let reloaded = load_config(&args).and_then(|config| server.reload(config));
+                if let Err(e) = reloaded {
+                    log::error!("Failed to reload configuration: {:#}", e);
+                }
+            }
+        }
+    }
+    server.shutdown().await
+}
+
+/// Requests a config reload on every SIGHUP.
+fn reload_requests() -> flume::Receiver<()> {
+    let (tx, rx) = flume::unbounded();
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup()).expect("failed to listen for SIGHUP");
+        while hangup.recv().await.is_some() {
+            let _ = tx.send(());
+        }
     });
-    tickloop.run();
-    Ok(())
+    rx
+}
+
+/// Completes on SIGINT or SIGTERM.
+#[cfg(unix)]
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
 }
@@ -0,0 +1,285 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::server::Server;
+use crate::system::{SysResult, SystemExecutor};
+
+/// Upper bounds of the tick duration buckets, in seconds.
+const TICK_BUCKETS: [f64; 8] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+impl Transport {
+    fn label(self) -> &'static str {
+        match self {
+            Transport::Tcp => "tcp",
+            Transport::Udp => "udp",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+impl Direction {
+    fn label(self) -> &'static str {
+        match self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        }
+    }
+}
+
+/// Counters kept by the server, the network tasks and the plugin,
+/// exported at `/metrics` in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    packets: Mutex<BTreeMap<(Transport, Direction, &'static str), u64>>,
+    tcp_decode_errors: AtomicU64,
+    udp_decode_errors: AtomicU64,
+    /// Packets a `try_send` gave up on, by channel.
+    dropped: Mutex<BTreeMap<&'static str, u64>>,
+    ticks: Mutex<TickHistogram>,
+    state: Mutex<ServerState>,
+}
+
+#[derive(Debug, Default)]
+struct TickHistogram {
+    buckets: [u64; TICK_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// What the server thread last reported about itself.
+#[derive(Debug, Default)]
+struct ServerState {
+    /// Ping of every connected car.
+    pings: Vec<(usize, u32)>,
+    session_index: usize,
+    session_type: u8,
+    session_elapsed: i64,
+}
+
+impl Metrics {
+    pub fn packet(&self, transport: Transport, direction: Direction, packet: &'static str) {
+        *self
+            .packets
+            .lock()
+            .unwrap()
+            .entry((transport, direction, packet))
+            .or_default() += 1;
+    }
+
+    pub fn decode_error(&self, transport: Transport) {
+        self.decode_errors(transport)
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn decode_errors(&self, transport: Transport) -> &AtomicU64 {
+        match transport {
+            Transport::Tcp => &self.tcp_decode_errors,
+            Transport::Udp => &self.udp_decode_errors,
+        }
+    }
+
+    pub fn dropped(&self, channel: &'static str) {
+        *self.dropped.lock().unwrap().entry(channel).or_default() += 1;
+    }
+
+    /// Counts a failed send on `channel`, for the `try_send`s that cannot do anything about it.
+    pub fn track_send<E>(&self, channel: &'static str, result: Result<(), E>) {
+        if result.is_err() {
+            self.dropped(channel);
+        }
+    }
+
+    pub fn record_tick(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let mut ticks = self.ticks.lock().unwrap();
+        for (bucket, bound) in ticks.buckets.iter_mut().zip(TICK_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        ticks.count += 1;
+        ticks.sum += seconds;
+    }
+
+    /// Renders every metric in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        {
+            let state = self.state.lock().unwrap();
+            header(&mut out, "assetto_clients", "gauge", "Connected clients.");
+            let _ = writeln!(out, "assetto_clients {}", state.pings.len());
+            header(
+                &mut out,
+                "assetto_client_ping_ms",
+                "gauge",
+                "Ping of each connected car.",
+            );
+            for (car_id, ping) in state.pings.iter() {
+                let _ = writeln!(
+                    out,
+                    "assetto_client_ping_ms{{car_id=\"{}\"}} {}",
+                    car_id, ping
+                );
+            }
+            header(
+                &mut out,
+                "assetto_session_index",
+                "gauge",
+                "Index of the current session.",
+            );
+            let _ = writeln!(out, "assetto_session_index {}", state.session_index);
+            header(
+                &mut out,
+                "assetto_session_type",
+                "gauge",
+                "Type of the current session, 0 booking, 1 practice, 2 qualify, 3 race.",
+            );
+            let _ = writeln!(out, "assetto_session_type {}", state.session_type);
+            header(
+                &mut out,
+                "assetto_session_elapsed_seconds",
+                "gauge",
+                "Time since the current session started, negative while waiting.",
+            );
+            let _ = writeln!(
+                out,
+                "assetto_session_elapsed_seconds {}",
+                state.session_elapsed as f64 / 1000.0
+            );
+        }
+
+        header(
+            &mut out,
+            "assetto_packets_total",
+            "counter",
+            "Packets by type.",
+        );
+        for ((transport, direction, packet), count) in self.packets.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "assetto_packets_total{{transport=\"{}\",direction=\"{}\",packet=\"{}\"}} {}",
+                transport.label(),
+                direction.label(),
+                packet,
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "assetto_decode_errors_total",
+            "counter",
+            "Received packets that could not be decoded.",
+        );
+        for transport in [Transport::Tcp, Transport::Udp] {
+            let _ = writeln!(
+                out,
+                "assetto_decode_errors_total{{transport=\"{}\"}} {}",
+                transport.label(),
+                self.decode_errors(transport).load(Ordering::Relaxed)
+            );
+        }
+
+        header(
+            &mut out,
+            "assetto_dropped_sends_total",
+            "counter",
+            "Packets dropped because a channel was full or closed.",
+        );
+        for (channel, count) in self.dropped.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "assetto_dropped_sends_total{{channel=\"{}\"}} {}",
+                channel, count
+            );
+        }
+
+        let ticks = self.ticks.lock().unwrap();
+        header(
+            &mut out,
+            "assetto_tick_duration_seconds",
+            "histogram",
+            "How long the systems of a tick took.",
+        );
+        for (count, bound) in ticks.buckets.iter().zip(TICK_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "assetto_tick_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "assetto_tick_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            ticks.count
+        );
+        let _ = writeln!(out, "assetto_tick_duration_seconds_sum {}", ticks.sum);
+        let _ = writeln!(out, "assetto_tick_duration_seconds_count {}", ticks.count);
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+pub fn register(systems: &mut SystemExecutor<Server>) {
+    systems.add_system("metrics", update_metrics);
+}
+
+/// Copies the client pings and the session state for `/metrics`.
+fn update_metrics(server: &mut Server) -> SysResult {
+    let pings = server
+        .clients
+        .iter()
+        .map(|client| (client.car_id, client.status().ping))
+        .collect();
+    let options = server.options.read().unwrap();
+    let mut state = server.metrics.state.lock().unwrap();
+    state.pings = pings;
+    state.session_index = options.sessions.get_current();
+    state.session_type = options.sessions.get_current_session().session_type.clone() as u8;
+    state.session_elapsed = options.sessions.elapsed_ms();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let metrics = Metrics::default();
+        metrics.packet(Transport::Udp, Direction::In, "CarUpdate");
+        metrics.packet(Transport::Udp, Direction::In, "CarUpdate");
+        metrics.decode_error(Transport::Tcp);
+        metrics.track_send("tcp", Err::<(), _>("full"));
+        metrics.track_send("tcp", Ok::<(), ()>(()));
+        metrics.record_tick(Duration::from_millis(2));
+
+        let text = metrics.render();
+        assert!(text.contains(
+            "assetto_packets_total{transport=\"udp\",direction=\"in\",packet=\"CarUpdate\"} 2\n"
+        ));
+        assert!(text.contains("assetto_decode_errors_total{transport=\"tcp\"} 1\n"));
+        assert!(text.contains("assetto_dropped_sends_total{channel=\"tcp\"} 1\n"));
+        assert!(text.contains("assetto_tick_duration_seconds_bucket{le=\"0.001\"} 0\n"));
+        assert!(text.contains("assetto_tick_duration_seconds_bucket{le=\"0.0025\"} 1\n"));
+        assert!(text.contains("# TYPE assetto_tick_duration_seconds histogram\n"));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{config::Config, session::Sessions, weather::SunAngle};
+use crate::{config::Config, results::Results, session::Sessions, weather::SunAngle};
 use crate::{
     dynamictrack::DynamicTrack,
     weather::{Temperature, Weather, Wind},
@@ -12,6 +12,7 @@ use std::{collections::HashMap, sync::Arc};
 use md5::Digest;
 //use crate::config::Weather;
 use std::fs;
+use std::path::Path;
 
 #[derive(Debug, Clone)]
 pub struct ServerOptions {
@@ -22,6 +23,13 @@ pub struct ServerOptions {
     pub grip_level: DynamicTrack,
     pub sessions: Sessions,
     pub laps: Laps,
+    pub results: Results,
+    pub welcome_message: String,
+    pub password: Option<String>,
+    pub admin_password: Option<String>,
+    pub banned_guids: Vec<String>,
+    /// Bans from admin commands, kept over reloads until the server restarts.
+    pub runtime_bans: Vec<String>,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -61,6 +69,58 @@ pub type CarString = String;
 
 impl ServerOptions {
     pub fn new(conf: Arc<Config>) -> Arc<RwLock<Self>> {
+        let weathers = ServerOptions::get_weathers(&conf);
+        let current_weather = weathers.first().unwrap().clone();
+        let mut grip_level = DynamicTrack::from(&conf.dynamictrack);
+        if let Some(grip) = conf.sessions.sessions.first().and_then(|s| s.grip) {
+            grip_level.on_new_session(Some(grip));
+        }
+
+        Arc::new(RwLock::new(Self {
+            weathers,
+            sun_angle: SunAngle::new(conf.sun_angle, conf.time_of_day_multiplier),
+            checksums: ServerOptions::get_checksums(
+                conf.server.content_dir.as_deref(),
+                &conf.cars,
+                conf.track.clone(),
+            ),
+            current_weather,
+            grip_level,
+            sessions: Sessions::from(&conf.sessions.sessions),
+            laps: Laps::default(),
+            results: Results::default(),
+            welcome_message: conf.server.welcome_message.clone(),
+            password: conf.game.password.clone(),
+            admin_password: conf.game.admin_password.clone(),
+            banned_guids: conf.game.banned_guids.clone(),
+            runtime_bans: Vec::new(),
+        }))
+    }
+
+    /// Takes over the parts of `conf` that can change while the server is running.
+    /// The current weather and session keep going until the session ends.
+    pub fn reload(&mut self, conf: &Config) {
+        self.weathers = ServerOptions::get_weathers(conf);
+        self.sessions.replace_upcoming(&conf.sessions.sessions);
+        self.grip_level.reconfigure(&conf.dynamictrack);
+        self.welcome_message = conf.server.welcome_message.clone();
+        self.password = conf.game.password.clone();
+        self.admin_password = conf.game.admin_password.clone();
+        self.banned_guids = conf.game.banned_guids.clone();
+    }
+
+    pub fn ban(&mut self, guid: String) {
+        self.runtime_bans.push(guid);
+    }
+
+    pub fn is_banned(&self, guid: &str) -> bool {
+        self.banned_guids
+            .iter()
+            .chain(&self.runtime_bans)
+            .any(|banned| banned == guid)
+    }
+
+    fn get_weathers(conf: &Config) -> Vec<Weather> {
         let mut weathers: Vec<Weather> = Vec::new();
         for i in conf.weathers.iter() {
             let road = Temperature::new(i.base_road, i.variation_road);
@@ -73,31 +133,37 @@ impl ServerOptions {
             );
             weathers.push(Weather::new(i.graphics.clone(), ambient, road, wind));
         }
-        let current_weather = weathers.first().unwrap().clone();
-
-        Arc::new(RwLock::new(Self {
-            weathers,
-            sun_angle: SunAngle::new(conf.sun_angle, conf.time_of_day_multiplier),
-            checksums: ServerOptions::get_checksums(&conf.cars, conf.track.clone()),
-            current_weather,
-            grip_level: DynamicTrack::from(&conf.dynamictrack),
-            sessions: Sessions::from(&conf.sessions.sessions),
-            laps: Laps::default(),
-        }))
+        weathers
     }
+    /// Picks the weather of the current session, or a random one if it has none.
     pub fn update_weather(&mut self) {
-        if let Some(weather) = self.weathers.choose(&mut rand::thread_rng()) {
+        let fixed = self
+            .sessions
+            .get_current_session()
+            .weather
+            .and_then(|i| self.weathers.get(i));
+        let weather = match fixed {
+            Some(weather) => Some(weather),
+            None => self.weathers.choose(&mut rand::thread_rng()),
+        };
+        if let Some(weather) = weather {
             self.current_weather = weather.clone();
         }
         self.current_weather.update();
     }
 
-    pub fn get_checksums(cars: &[crate::config::Car], track: String) -> HashMap<String, String> {
+    pub fn get_checksums(
+        content_dir: Option<&Path>,
+        cars: &[crate::config::Car],
+        track: String,
+    ) -> HashMap<String, String> {
         let mut ret = HashMap::new();
 
         for car in cars.iter() {
-            let path = format!("content/cars/{}/data.acd", car.model);
-            if let Ok(content) = fs::read(path) {}
+            if let Some(content_dir) = content_dir {
+                let path = content_dir.join("cars").join(&car.model).join("data.acd");
+                if let Ok(content) = fs::read(path) {}
+            }
             //            let md5 = md5::compute(content);
             //let md5_string = format!("{:x}", md5);
             ret.insert(car.model.clone(), "fak".into());
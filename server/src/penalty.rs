@@ -0,0 +1,48 @@
+use protocol::packets::server::KickReason;
+
+use crate::event::ServerEvent;
+use crate::server::Server;
+use crate::system::{SysResult, SystemExecutor};
+
+/// Distance in meters a car has to drive before its contacts per km are checked.
+const MIN_DISTANCE: f32 = 1000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PenaltyReason {
+    /// More contacts per km than `max_contacts_per_km`.
+    TooManyContacts,
+}
+
+pub fn register(systems: &mut SystemExecutor<Server>) {
+    systems.add_system("penalties", check_contacts);
+}
+
+/// Kicks drivers that make contact with other cars more often than `max_contacts_per_km`.
+fn check_contacts(server: &mut Server) -> SysResult {
+    let max_contacts_per_km = server.config.game.max_contacts_per_km;
+    if max_contacts_per_km == 0 {
+        return Ok(());
+    }
+
+    let offenders: Vec<usize> = server
+        .clients
+        .iter()
+        .filter(|client| {
+            let status = client.status();
+            status.distance_driven >= MIN_DISTANCE
+                && status.contacts as f32 / (status.distance_driven / 1000.0)
+                    > max_contacts_per_km as f32
+        })
+        .map(|client| client.car_id)
+        .collect();
+
+    for car_id in offenders {
+        log::info!("Kicking {} for too many contacts", car_id);
+        server.emit(ServerEvent::Penalty {
+            car_id,
+            reason: PenaltyReason::TooManyContacts,
+        });
+        server.kick(car_id, KickReason::Kick);
+    }
+    Ok(())
+}
@@ -0,0 +1,136 @@
+use crate::config::Config;
+use crate::metrics::Metrics;
+use crate::server::Server;
+use crate::system::{SysResult, SystemExecutor};
+
+use anyhow::{Context, Result};
+use protocol::io::{Readable, Writeable};
+use protocol::packets::client::UdpPlugin as PluginCommand;
+use protocol::packets::server::{KickReason, SessionInfoPlugin, SessionInfoPlugin1, UdpPlugin};
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+
+const MAX_COMMAND_SIZE: usize = 2048;
+
+/// Sends server events to the UDP plugin configured in `[plugin]`
+/// and receives its commands.
+pub struct Plugin {
+    socket: Option<UdpSocket>,
+    address: Option<SocketAddr>,
+    metrics: Arc<Metrics>,
+}
+
+impl Plugin {
+    pub async fn bind(config: &Config, metrics: Arc<Metrics>) -> Result<Self> {
+        let plugin = match &config.plugin {
+            Some(plugin) => plugin,
+            None => {
+                return Ok(Self {
+                    socket: None,
+                    address: None,
+                    metrics,
+                })
+            }
+        };
+
+        let address = format!("{}:{}", config.server.address, plugin.local_port);
+        let socket = UdpSocket::bind(address)
+            .await
+            .context("failed to bind to plugin port - maybe a server is already running?")?;
+
+        log::info!(
+            "Sending plugin events from {}:{} to {}",
+            config.server.address,
+            plugin.local_port,
+            plugin.address
+        );
+        Ok(Self {
+            socket: Some(socket),
+            address: Some(plugin.address),
+            metrics,
+        })
+    }
+
+    pub fn send(&self, packet: UdpPlugin) {
+        if let (Some(socket), Some(addr)) = (&self.socket, self.address) {
+            let mut buffer = Vec::new();
+            if packet.write(&mut buffer).is_ok() {
+                log::trace!("plugin: {:?}", packet);
+                let result = socket.try_send_to(&buffer, addr);
+                self.metrics.track_send("plugin", result.map(|_| ()));
+            }
+        }
+    }
+}
+
+impl Plugin {
+    /// Returns the commands the plugin has sent since the last call.
+    pub fn receive(&self) -> Vec<PluginCommand> {
+        let mut commands = Vec::new();
+        let (socket, address) = match (&self.socket, self.address) {
+            (Some(socket), Some(address)) => (socket, address),
+            _ => return commands,
+        };
+
+        let mut buf = [0; MAX_COMMAND_SIZE];
+        while let Ok((len, addr)) = socket.try_recv_from(&mut buf) {
+            if addr != address {
+                log::debug!("Ignoring plugin command from {}", addr);
+                continue;
+            }
+            match PluginCommand::read(&mut Cursor::new(&buf[..len])) {
+                Ok(command) => commands.push(command),
+                Err(e) => log::debug!("Failed to decode plugin command: {}", e),
+            }
+        }
+        commands
+    }
+}
+
+pub fn register(systems: &mut SystemExecutor<Server>) {
+    systems.add_system("plugins", handle_plugin_commands);
+}
+
+fn handle_plugin_commands(server: &mut Server) -> SysResult {
+    for command in server.plugin.receive() {
+        log::debug!("plugin command: {:?}", command);
+        match command {
+            PluginCommand::ChatPlugin(c) => {
+                server.send_chat(c.car_id.into(), c.msg);
+            }
+            PluginCommand::BroadcastPlugin(b) => server.broadcast_chat(b.msg),
+            PluginCommand::KickPlugin(k) => server.kick(k.car_id.into(), KickReason::Kick),
+            PluginCommand::NextSessionPlugin(_) => server.next_session(),
+            PluginCommand::RestartSessionPlugin(_) => server.restart_session(),
+            PluginCommand::SessionInfoPlugin(_) => {
+                let info = session_info_response(server.session_info());
+                server.plugin.send(UdpPlugin::SessionInfoPlugin1(info));
+            }
+            _ => log::debug!("Unsupported plugin command"),
+        }
+    }
+    Ok(())
+}
+
+/// The plugin gets the same session info as a reply to its request, under another packet id.
+fn session_info_response(info: SessionInfoPlugin) -> SessionInfoPlugin1 {
+    SessionInfoPlugin1 {
+        protocol_version: info.protocol_version,
+        session_index: info.session_index,
+        sessions_len: info.sessions_len,
+        server_name: info.server_name,
+        track: info.track,
+        track_config: info.track_config,
+        name: info.name,
+        typ: info.typ,
+        time: info.time,
+        laps: info.laps,
+        wait_time: info.wait_time,
+        ambient_temp: info.ambient_temp,
+        road_temp: info.road_temp,
+        weather_graphics: info.weather_graphics,
+        elapsed_ms: info.elapsed_ms,
+    }
+}
@@ -1,17 +1,19 @@
 use flume::Receiver;
 use flume::Sender;
 use protocol::{
-    io::{Readable, Writeable},
     packets::{client::TestClient, server::TestServer},
     Codec,
 };
-use std::fmt::Debug;
 use std::io::{self, ErrorKind};
+use std::sync::Arc;
+
+use crate::metrics::{Direction, Metrics, Transport};
 use std::time::Duration;
 
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::tcp::{OwnedReadHalf, OwnedWriteHalf},
+    sync::Notify,
     time::timeout,
 };
 
@@ -55,18 +57,28 @@ where
     codec: Codec,
     buffer: [u8; 512],
     received_packets: Sender<TestClient>,
+    activity: Arc<Notify>,
+    metrics: Arc<Metrics>,
 }
 
 impl<T> Reader<T>
 where
     T: StreamTraitRead,
 {
-    pub fn new(stream: T, received_packets: Sender<TestClient>) -> Self {
+    /// `activity` is notified whenever a packet is queued to `received_packets`.
+    pub fn new(
+        stream: T,
+        received_packets: Sender<TestClient>,
+        activity: Arc<Notify>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
         Self {
             stream,
             codec: Codec::new(),
             buffer: [0; 512],
             received_packets,
+            activity,
+            metrics,
         }
     }
 
@@ -76,6 +88,7 @@ where
             log::debug!("Read: {:?}", packet);
 
             let result = self.received_packets.send_async(packet).await;
+            self.activity.notify_one();
             if result.is_err() {
                 // server dropped connection
                 log::debug!("drop");
@@ -84,11 +97,20 @@ where
         }
     }
 
-    pub async fn read<P: Readable>(&mut self) -> anyhow::Result<P> {
+    pub async fn read(&mut self) -> anyhow::Result<TestClient> {
         // Keep reading bytes and trying to get the packet.
         loop {
-            if let Some(packet) = self.codec.next_packet::<P>()? {
-                return Ok(packet);
+            match self.codec.next_packet::<TestClient>() {
+                Ok(Some(packet)) => {
+                    self.metrics
+                        .packet(Transport::Tcp, Direction::In, packet.name());
+                    return Ok(packet);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    self.metrics.decode_error(Transport::Tcp);
+                    return Err(e);
+                }
             }
 
             let duration = Duration::from_secs(10);
@@ -111,18 +133,20 @@ where
     codec: Codec,
     packets_to_send: Receiver<TestServer>,
     buffer: Vec<u8>,
+    metrics: Arc<Metrics>,
 }
 
 impl<T> Writer<T>
 where
     T: StreamTraitWrite,
 {
-    pub fn new(stream: T, packets_to_send: Receiver<TestServer>) -> Self {
+    pub fn new(stream: T, packets_to_send: Receiver<TestServer>, metrics: Arc<Metrics>) -> Self {
         Self {
             stream,
             codec: Codec::new(),
             packets_to_send,
             buffer: Vec::new(),
+            metrics,
         }
     }
 
@@ -133,7 +157,9 @@ where
         Ok(())
     }
 
-    pub async fn write(&mut self, packet: impl Writeable + Debug) -> anyhow::Result<()> {
+    pub async fn write(&mut self, packet: TestServer) -> anyhow::Result<()> {
+        self.metrics
+            .packet(Transport::Tcp, Direction::Out, packet.name());
         self.codec.encode(&packet, &mut self.buffer)?;
         self.stream.write_all(&self.buffer).await?;
         self.buffer.clear();
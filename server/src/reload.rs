@@ -0,0 +1,140 @@
+use std::collections::BTreeSet;
+use std::sync::RwLock;
+
+use anyhow::bail;
+use toml::Value;
+
+use crate::car::Cars;
+use crate::config::Config;
+use crate::option::ServerOptions;
+
+/// Applies the parts of `new` that can change mid-event: the welcome message,
+/// weathers, sessions after the current one, passwords, bans, entry list
+/// reservations and dynamic track. Nothing is changed if `new` is invalid.
+pub fn reload(
+    current: &Config,
+    new: &Config,
+    options: &RwLock<ServerOptions>,
+    cars: &Cars,
+) -> anyhow::Result<()> {
+    new.validate()?;
+    let same_cars = current.cars.len() == new.cars.len()
+        && current
+            .cars
+            .iter()
+            .zip(&new.cars)
+            .all(|(a, b)| a.model == b.model && a.skin == b.skin);
+    if !same_cars {
+        bail!("cars can only be changed by restarting the server");
+    }
+    let restart_only = restart_only_changes(current, new);
+    for field in restart_only.iter() {
+        log::warn!("{} changed, restart the server to apply it", field);
+    }
+
+    options.write().unwrap().reload(new);
+    cars.update_entry_list(&new.cars);
+    match restart_only.len() {
+        0 => log::info!("Reloaded config"),
+        n => log::warn!("Reloaded config, {} changes wait for a restart", n),
+    }
+    Ok(())
+}
+
+/// Settings `reload` applies, changes anywhere else need a restart. The cars are
+/// checked on their own: their reservations reload, models and skins cannot change.
+const RELOADED: [&str; 8] = [
+    "server.welcome_message",
+    "game.password",
+    "game.admin_password",
+    "game.banned_guids",
+    "dynamictrack",
+    "weathers",
+    "sessions.sessions",
+    "cars",
+];
+
+/// Paths of the settings that differ between `current` and `new` but are not reloaded.
+fn restart_only_changes(current: &Config, new: &Config) -> Vec<String> {
+    let mut changed = Vec::new();
+    match (Value::try_from(current), Value::try_from(new)) {
+        (Ok(current), Ok(new)) => diff("", &current, &new, &mut changed),
+        _ => log::warn!("Could not compare the configs, restart the server to apply every change"),
+    }
+    changed
+}
+
+fn diff(path: &str, current: &Value, new: &Value, changed: &mut Vec<String>) {
+    if current == new || RELOADED.contains(&path) {
+        return;
+    }
+    match (current, new) {
+        (Value::Table(current), Value::Table(new)) => {
+            let keys: BTreeSet<&String> = current.keys().chain(new.keys()).collect();
+            for key in keys {
+                let path = match path {
+                    "" => key.clone(),
+                    _ => format!("{}.{}", path, key),
+                };
+                match (current.get(key), new.get(key)) {
+                    (Some(current), Some(new)) => diff(&path, current, new, changed),
+                    // a setting that was added or removed, e.g. a password
+                    _ if RELOADED.contains(&path.as_str()) => {}
+                    _ => changed.push(path),
+                }
+            }
+        }
+        _ => changed.push(path.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn reload_keeps_current_session_and_rejects_invalid_configs() {
+        let current = Config::default();
+        let options = ServerOptions::new(Arc::new(Config::default()));
+        let cars = Cars::new(Arc::new(Config::default()));
+        options.write().unwrap().ban("76561198000000000".into());
+
+        let mut new = Config::default();
+        new.game.password = Some("secret".into());
+        new.sessions.sessions[0].name = "Renamed".into();
+        new.sessions.sessions.push(new.sessions.sessions[0].clone());
+        new.cars[0].guid = Some("123".into());
+        reload(&current, &new, &options, &cars).unwrap();
+
+        {
+            let options = options.read().unwrap();
+            assert_eq!(options.password.as_deref(), Some("secret"));
+            assert_eq!(options.sessions.get_current_session().name, "Qualify");
+            assert_eq!(options.sessions.len(), 2);
+            assert!(options.is_banned("76561198000000000"));
+        }
+        assert_eq!(cars.lock().unwrap()[0].entry_guid.as_deref(), Some("123"));
+
+        let mut invalid = Config::default();
+        invalid.game.password = Some("other".into());
+        invalid.sessions.sessions[0].time = 0;
+        assert!(reload(&current, &invalid, &options, &cars).is_err());
+        assert_eq!(options.read().unwrap().password.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn lists_the_changes_a_reload_does_not_apply() {
+        let current = Config::default();
+        let mut new = Config::default();
+        new.game.password = Some("secret".into());
+        new.game.abs_allowed = 1;
+        new.server.max_clients += 1;
+        new.chat.max_length += 1;
+        new.cars[0].guid = Some("123".into());
+        assert_eq!(
+            restart_only_changes(&current, &new),
+            vec!["chat.max_length", "game.abs_allowed", "server.max_clients"]
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+/// Result line of a driver in the current session.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ResultEntry {
+    pub driver_name: String,
+    pub driver_guid: String,
+    pub car_id: usize,
+    pub car_model: String,
+    pub laps: u32,
+    /// Milliseconds since server start when the driver left, if they did.
+    pub left_at: Option<u32>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Results {
+    entries: Vec<ResultEntry>,
+}
+
+impl Results {
+    fn entry_mut(&mut self, guid: &str, car_id: usize) -> Option<&mut ResultEntry> {
+        self.entries
+            .iter_mut()
+            .find(|e| e.driver_guid == guid && e.car_id == car_id)
+    }
+
+    /// Adds `entry`, replacing the entry of the same driver in the same car.
+    pub fn record(&mut self, entry: ResultEntry) {
+        match self.entry_mut(&entry.driver_guid, entry.car_id) {
+            Some(existing) => *existing = entry,
+            None => self.entries.push(entry),
+        }
+    }
+
+    pub fn entries(&self) -> &[ResultEntry] {
+        &self.entries
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Writes the entries as JSON into `dir`, named after the current time and `session`.
+    pub fn write(&self, dir: &Path, session: &str) -> anyhow::Result<PathBuf> {
+        fs::create_dir_all(dir)?;
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let session: String = session
+            .chars()
+            .map(|c| if c.is_alphanumeric() { c } else { '_' })
+            .collect();
+        let path = dir.join(format!("{}_{}.json", secs, session.to_uppercase()));
+        fs::write(&path, serde_json::to_string_pretty(&self.entries)?)?;
+        Ok(path)
+    }
+}
@@ -1,3 +1,5 @@
+use std::future::Future;
+use std::net::SocketAddr;
 use std::ops::Add;
 use std::time::Duration;
 use std::{net::IpAddr, sync::Arc, time::Instant};
@@ -6,20 +8,49 @@ use flume::{Receiver, Sender};
 
 use hyper::client;
 use protocol::io::WideString;
+use protocol::packets::client::CollisionType;
 use protocol::packets::client::UpdateUpdAddress;
 use protocol::packets::server::{
-    Bops, CarConnected, CarList, ChangeTireCompound, Chat, ClientDisconnect, DamageUpdate, Lap,
-    LapCompleted, LobbyCheckMessage, MegaPacket, P2PCount, Ping, PositionUpdate,
-    UpdateUpdAddress as UpdateUpdAddressS, Weather, WelcomeMessage,
+    Bops, CarConnected, CarList, ChangeTireCompound, ClientDisconnect, ClientEventPlugin,
+    ConnectionClosedPlugin, DamageUpdate, EndSessionPlugin, Kick, KickReason, Lap, LapCompleted,
+    LobbyCheckMessage, MegaPacket, P2PCount, Ping, PositionUpdate, SectorSplit, SessionClosed,
+    SessionInfoPlugin, SessionType, UdpPlugin, UpdateUpdAddress as UpdateUpdAddressS, Weather,
+    WelcomeMessage,
 };
 use protocol::packets::{client::TestClient, server::TestServer};
 use rand::distributions::uniform::UniformSampler;
 
+use crate::admin;
+use crate::chat::{self, Moderation};
+use crate::event::{EventBus, ServerEvent};
+use crate::interest::{self, TrackPosition};
+use crate::listener::{ConnectionHandles, Listener};
+use crate::metrics::Metrics;
+use crate::option::Laps;
+use crate::plugin::Plugin;
+use crate::results::ResultEntry;
+use crate::system::SystemExecutor;
+use crate::tickloop::TickLoop;
 use crate::udpserver::UdpServerMessage;
-use crate::{car::Cars, client::Clients, config::Config, listener::Listener, ServerOptions};
+use crate::vote::{VoteKind, Votes};
+use crate::{car::Cars, client::Clients, config::Config, ServerOptions};
 use crate::{client::Client, udpserver::UdpServer};
 use crate::{client::ClientId, udpserver::UdpClientMessage};
 use std::sync::RwLock;
+use tokio::{
+    sync::{mpsc, Notify},
+    task::AbortHandle,
+};
+
+/// Version of the UDP plugin protocol reported in `SessionInfoPlugin`.
+const PLUGIN_PROTOCOL_VERSION: u8 = 4;
+
+/// Car id clients show as the server in chat.
+pub const SERVER_CAR_ID: u8 = 255;
+
+/// How long shutdown waits for packets queued to clients to be written.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct Server {
     pub config: Arc<Config>,
     pub options: Arc<RwLock<ServerOptions>>,
@@ -29,7 +60,17 @@ pub struct Server {
     pub udp_packets: Receiver<UdpClientMessage>,
     pub udp_packets_to_send: Sender<UdpServerMessage>,
     pub start_time: Instant,
-    cars: Arc<Cars>,
+    pub cars: Arc<Cars>,
+    pub plugin: Plugin,
+    pub votes: Votes,
+    tick: u64,
+    tcp_activity: Arc<Notify>,
+    tcp_addr: SocketAddr,
+    listener: AbortHandle,
+    shutdown_complete: mpsc::Receiver<()>,
+    events: EventBus,
+    pub metrics: Arc<Metrics>,
+    pub moderation: Moderation,
 }
 
 #[derive(Debug)]
@@ -42,6 +83,8 @@ pub struct NewPlayer {
     pub received_packets: Receiver<TestClient>,
     pub packets_to_send: Sender<TestServer>,
     pub udp_packets_to_send: Sender<UdpServerMessage>,
+    pub tcp_reader: AbortHandle,
+    pub metrics: Arc<Metrics>,
 }
 
 impl Server {
@@ -51,24 +94,29 @@ impl Server {
         options: Arc<RwLock<ServerOptions>>,
         udp_packets: Receiver<UdpClientMessage>,
         udp_packets_to_send: Sender<UdpServerMessage>,
+        events: EventBus,
+        metrics: Arc<Metrics>,
     ) -> anyhow::Result<Self> {
         let (new_players_tx, new_players) = flume::bounded(4);
         let start_time = Instant::now();
-        Listener::start(
-            start_time.clone(),
-            Arc::clone(&config),
-            Arc::clone(&options),
-            cars.clone(),
-            new_players_tx,
-            udp_packets_to_send.clone(),
-        )
+        let moderation = Moderation::new(&config.chat)?;
+        let plugin = Plugin::bind(&config, Arc::clone(&metrics)).await?;
+        let tcp_activity = Arc::new(Notify::new());
+        let (shutdown_complete_tx, shutdown_complete) = mpsc::channel(1);
+        let (tcp_addr, listener) = Listener::start(ConnectionHandles {
+            start_time,
+            config: Arc::clone(&config),
+            options: Arc::clone(&options),
+            cars: cars.clone(),
+            new_players: new_players_tx,
+            udp_packets_to_send: udp_packets_to_send.clone(),
+            tcp_activity: Arc::clone(&tcp_activity),
+            shutdown_complete: shutdown_complete_tx,
+            metrics: Arc::clone(&metrics),
+        })
         .await?;
 
-        log::info!(
-            "Server is listening tcp on {}:{}",
-            config.server.address,
-            config.server.tcp_port
-        );
+        log::info!("Server is listening tcp on {}", tcp_addr);
 
         Ok(Self {
             config,
@@ -79,11 +127,76 @@ impl Server {
             udp_packets_to_send,
             udp_packets: udp_packets,
             start_time,
+            plugin,
+            votes: Votes::default(),
+            tick: 0,
+            tcp_activity,
+            tcp_addr,
+            listener,
+            shutdown_complete,
+            events,
+            metrics,
+            moderation,
         })
     }
+
+    pub fn tcp_addr(&self) -> SocketAddr {
+        self.tcp_addr
+    }
+
+    /// Publishes `event` to everyone subscribed to the server's events.
+    pub fn emit(&self, event: ServerEvent) {
+        self.events.publish(event);
+    }
+
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// Tears down a client: frees its car, stops its connection and
+    /// lets the other clients, the plugin and the session results know.
     pub fn remove_client(&mut self, id: ClientId) {
         let client = self.clients.remove(id);
+        client.close();
         log::debug!("Removed client for {}", client.car_id);
+
+        self.cars.save_progress(client.car_id, &client.status());
+        let car = self.cars.lock().unwrap().get(client.car_id).cloned();
+        self.cars.remove_car(client.car_id);
+
+        self.broadcast_with(|c| c.send_packet(TestServer::ClientDisconnect((&client).into())));
+
+        if let Some(car) = car {
+            let driver = car.driver.clone().unwrap_or_default();
+            self.emit(ServerEvent::DriverLeft {
+                car_id: client.car_id,
+                name: driver.name.clone(),
+                guid: client.guid.clone(),
+            });
+            self.plugin
+                .send(UdpPlugin::ConnectionClosedPlugin(ConnectionClosedPlugin {
+                    name: driver.name.clone(),
+                    guid: client.guid.clone(),
+                    car_id: client.car_id as u8,
+                    car_model: car.model.clone(),
+                    car_skin: car.skin.clone(),
+                }));
+
+            self.options.write().unwrap().results.record(ResultEntry {
+                driver_name: driver.name,
+                driver_guid: client.guid.clone(),
+                car_id: client.car_id,
+                car_model: car.model,
+                laps: client.status().laps,
+                left_at: Some(self.timestamp()),
+            });
+        }
+    }
+
+    pub fn remove_disconnected_clients(&mut self) {
+        for id in self.clients.disconnected() {
+            self.remove_client(id);
+        }
     }
 
     fn create_client(&mut self, new_player: NewPlayer) -> ClientId {
@@ -95,16 +208,234 @@ impl Server {
     pub fn accept_new_players(&mut self) -> Vec<ClientId> {
         let mut clients = Vec::new();
         for player in self.new_players.clone().try_iter() {
-            let car = self.cars.lock().unwrap().get(player.car_id).unwrap();
-            if let Some(old_client) = self.clients.iter().find(|x| x.guid == player.guid) {
-                //old_client.disconnect("Logged in from another location!");
-            }
-            let id = self.create_client(player);
-            clients.push(id);
+            clients.push(self.accept_new_player(player));
         }
         clients
     }
 
+    pub fn accept_new_player(&mut self, player: NewPlayer) -> ClientId {
+        if let Some(old_id) = self.clients.get_id_from_guid(&player.guid) {
+            let old_car_id = self.clients.get(old_id).map(|old_client| {
+                old_client.send_chat("Logged in from another location");
+                self.cars
+                    .save_progress(old_client.car_id, &old_client.status());
+                old_client.car_id
+            });
+            match old_car_id {
+                Some(old_car_id) if old_car_id != player.car_id => {
+                    // the driver picked another car, the old one is free for anyone
+                    self.remove_client(old_id);
+                    self.cars.free_car(old_car_id);
+                }
+                // the new connection already owns the car, so only close the stale one
+                _ => self.clients.remove(old_id).close(),
+            }
+        }
+        let car = self.cars.lock().unwrap().get(player.car_id).cloned();
+        let id = self.create_client(player);
+        if let (Some(car), Some(client)) = (car, self.clients.get(id)) {
+            client.status_mut().restore(&car);
+            self.emit(ServerEvent::DriverJoined {
+                car_id: client.car_id,
+                name: car.driver.map(|driver| driver.name).unwrap_or_default(),
+                guid: client.guid.clone(),
+            });
+        }
+        id
+    }
+
+    /// Runs the server until `shutdown` completes or every packet source is closed.
+    /// Packets are handled as soon as they arrive, `systems` run on every tick of `tick_loop`.
+    pub async fn run(
+        mut self,
+        mut tick_loop: TickLoop,
+        mut systems: SystemExecutor<Server>,
+        shutdown: impl Future<Output = ()>,
+    ) {
+        let new_players = self.new_players.clone();
+        let udp_packets = self.udp_packets.clone();
+        let tcp_activity = Arc::clone(&self.tcp_activity);
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                Ok(player) = new_players.recv_async() => {
+                    self.accept_new_player(player);
+                }
+                Ok(message) = udp_packets.recv_async() => {
+                    self.handle_udp_message(message);
+                    self.handle_udp_messages();
+                }
+                _ = tcp_activity.notified() => {
+                    self.handle_tcp_packets();
+                }
+                _ = tick_loop.tick() => {
+                    let start = Instant::now();
+                    systems.run(&mut self);
+                    let elapsed = start.elapsed();
+                    tick_loop.record(elapsed);
+                    self.metrics.record_tick(elapsed);
+                }
+                _ = &mut shutdown => break,
+                else => break,
+            }
+            self.remove_disconnected_clients();
+        }
+        self.shutdown().await;
+    }
+
+    /// Says goodbye to the clients, writes the session results and closes every
+    /// connection, waiting at most `SHUTDOWN_TIMEOUT` for queued packets to be written.
+    pub async fn shutdown(mut self) {
+        log::info!("Shutting down");
+        self.listener.abort();
+        self.broadcast_chat("Server is shutting down");
+        self.flush_results();
+        self.broadcast_with(|c| c.send_packet(TestServer::SessionClosed(SessionClosed {})));
+        self.close();
+
+        // dropping the clients lets their writers finish
+        let Server {
+            mut shutdown_complete,
+            ..
+        } = self;
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, shutdown_complete.recv())
+            .await
+            .is_err()
+        {
+            log::warn!("Connections did not close within {:?}", SHUTDOWN_TIMEOUT);
+        }
+    }
+
+    /// Clears the poison a panicking system leaves on the state shared with the
+    /// other threads, so the systems after it and the HTTP server keep working.
+    pub fn clear_poison(&self) {
+        self.options.clear_poison();
+        self.cars.clear_poison();
+    }
+
+    /// Disconnects every client.
+    pub fn close(&mut self) {
+        for client in self.clients.iter() {
+            client.disconnect();
+        }
+        self.remove_disconnected_clients();
+    }
+
+    /// Writes the results of the current session, including drivers still connected.
+    pub fn flush_results(&mut self) {
+        let mut options = self.options.write().unwrap();
+        for client in self.clients.iter() {
+            let car = match self.cars.lock().unwrap().get(client.car_id).cloned() {
+                Some(car) => car,
+                None => continue,
+            };
+            options.results.record(ResultEntry {
+                driver_name: car.driver.map(|driver| driver.name).unwrap_or_default(),
+                driver_guid: client.guid.clone(),
+                car_id: client.car_id,
+                car_model: car.model,
+                laps: client.status().laps,
+                left_at: None,
+            });
+        }
+        if options.results.entries().is_empty() {
+            return;
+        }
+        let session = options.sessions.get_current_session().name.clone();
+        match options
+            .results
+            .write(&self.config.server.results_dir, &session)
+        {
+            Ok(path) => log::info!("Wrote results to {}", path.display()),
+            Err(e) => log::error!("Failed to write results: {:?}", e),
+        }
+    }
+
+    /// Moves on to the next session and lets the clients and the plugin know.
+    pub fn next_session(&mut self) {
+        self.flush_results();
+        self.plugin
+            .send(UdpPlugin::EndSessionPlugin(EndSessionPlugin {}));
+        {
+            let mut options = self.options.write().unwrap();
+            if options.sessions.get_current_session().session_type == SessionType::Booking {
+                self.cars.end_booking();
+            }
+            options.sessions.next_session();
+            let grip = options.sessions.get_current_session().grip;
+            options.grip_level.on_new_session(grip);
+            options.laps = Laps::default();
+            options.results.clear();
+        }
+        self.start_session();
+    }
+
+    pub fn restart_session(&mut self) {
+        {
+            let mut options = self.options.write().unwrap();
+            options.sessions.start();
+            options.laps = Laps::default();
+            options.results.clear();
+        }
+        self.start_session();
+    }
+
+    fn start_session(&mut self) {
+        let event = {
+            let options = self.options.read().unwrap();
+            let session = options.sessions.get_current_session();
+            ServerEvent::SessionChanged {
+                index: options.sessions.get_current(),
+                name: session.name.clone(),
+                session_type: session.session_type.clone() as u8,
+            }
+        };
+        log::info!("Starting session {:?}", event);
+        self.emit(event);
+        for client in self.clients.iter() {
+            client.status_mut().laps = 0;
+            self.cars.save_progress(client.car_id, &client.status());
+            client.send_session_update(self.options.clone());
+        }
+        self.plugin
+            .send(UdpPlugin::SessionInfoPlugin(self.session_info()));
+    }
+
+    pub fn session_info(&self) -> SessionInfoPlugin {
+        let options = self.options.read().unwrap();
+        let session = options.sessions.get_current_session();
+        let weather = options.current_weather();
+        SessionInfoPlugin {
+            protocol_version: PLUGIN_PROTOCOL_VERSION,
+            session_index: options.sessions.get_current() as u8,
+            sessions_len: options.sessions.len() as u8,
+            server_name: self.config.server.name.clone(),
+            track: self.config.track.clone(),
+            track_config: self.config.get_track_config(),
+            name: session.name.clone(),
+            typ: session.session_type.clone() as u8,
+            time: (session.end.as_secs() / 60) as u16,
+            laps: session.laps,
+            wait_time: session.wait.as_secs() as u16,
+            ambient_temp: weather.ambient.temp as u8,
+            road_temp: weather.road.temp as u8,
+            weather_graphics: weather.graphics.clone(),
+            elapsed_ms: options.sessions.elapsed_ms() as i32,
+        }
+    }
+
+    /// Tells everyone `car_id` was kicked and disconnects it.
+    pub fn kick(&mut self, car_id: usize, reason: KickReason) {
+        if let Some(client) = self.clients.get_from_car_id(car_id) {
+            let packet = TestServer::Kick(Kick {
+                car_id: car_id as u8,
+                reason: reason as u8,
+            });
+            self.broadcast_with(|c| c.send_packet(packet.clone()));
+            client.disconnect();
+        }
+    }
+
     pub fn broadcast_with(&self, mut callback: impl FnMut(&Client)) {
         for client in self.clients.iter() {
             callback(client);
@@ -116,7 +447,27 @@ impl Server {
         }
     }
 
+    /// Sends `message` from the server to the driver of `car_id`,
+    /// returns false if nobody is driving it.
+    pub fn send_chat(&self, car_id: usize, message: impl Into<String>) -> bool {
+        match self.clients.get_from_car_id(car_id) {
+            Some(client) => {
+                client.send_chat(message);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sends `message` from the server to every driver.
+    pub fn broadcast_chat(&self, message: impl Into<String>) {
+        let message = message.into();
+        self.broadcast_with(|c| c.send_chat(message.as_str()));
+    }
+
     pub fn handle_tcp_packets(&mut self) {
+        let mut votes = Vec::new();
+        let mut chats = Vec::new();
         for client in self.clients.iter() {
             for packet in client.received_packets() {
                 match packet {
@@ -140,10 +491,7 @@ impl Server {
                     }
                     TestClient::CarlistRequest(carlist_req) => client
                         .send_packet(TestServer::CarList(self.cars.to_packet(carlist_req.index))),
-                    TestClient::Disconnect(_) => self.broadcast_except_with(client, |c| {
-                        c.send_packet(TestServer::ClientDisconnect(client.into()));
-                        c.disconnect();
-                    }),
+                    TestClient::Disconnect(_) => client.disconnect(),
 
                     TestClient::Checksum(checksum) => {
                         for i in checksum.checksums.iter() {
@@ -151,13 +499,7 @@ impl Server {
                         }
                     }
                     TestClient::Chat(chat) => {
-                        log::debug!("{}: {}", client.car_id, chat.msg);
-                        self.broadcast_except_with(client, |c| {
-                            c.send_packet(TestServer::Chat(Chat {
-                                car_id: client.car_id as u8,
-                                msg: chat.msg.clone().into(),
-                            }))
-                        });
+                        chats.push((client.car_id, chat.msg.to_string()));
                     }
                     TestClient::LapCompleted(l) => {
                         let elapsed =
@@ -167,23 +509,31 @@ impl Server {
                         client.status_mut().last_lap_timestamp = Instant::now();
                         self.options.write().unwrap().grip_level.on_lap_complete();
 
-                        let has_completed_last_lap = client.status().laps
-                            >= self
-                                .options
-                                .read()
-                                .unwrap()
-                                .sessions
-                                .get_current_session()
-                                .laps as u32;
+                        client.add_lap();
+                        self.cars.save_progress(client.car_id, &client.status());
+                        let laps = client.status().laps;
+                        let has_completed_last_lap = self
+                            .options
+                            .write()
+                            .unwrap()
+                            .sessions
+                            .complete_lap(client.car_id, laps, self.config.game.has_extra_lap);
+                        if has_completed_last_lap {
+                            log::info!("{} finished the race after {} laps", client.car_id, laps);
+                        }
 
                         self.options.write().unwrap().laps.add_lap(Lap {
                             car_id: client.car_id as u8,
                             laptime: l.laptime,
-                            lap_count: client.status().laps as u16,
+                            lap_count: laps as u16,
                             has_completed_last_lap,
                         });
-
-                        client.add_lap();
+                        self.emit(ServerEvent::LapCompleted {
+                            car_id: client.car_id,
+                            laptime: l.laptime,
+                            cuts: l.cuts,
+                            laps: client.status().laps,
+                        });
 
                         self.broadcast_except_with(client, |c| {
                             c.send_packet(TestServer::LapCompleted(LapCompleted {
@@ -195,129 +545,229 @@ impl Server {
                             }))
                         })
                     }
-                    TestClient::Pulse(_) => todo!(),
+                    TestClient::Pulse(_) => {}
                     TestClient::ChangeTireCompound(t) => {
                         log::debug!("{} changed tires to {}", client.car_id, t.tire_compound);
                         client.status_mut().current_tyre_compound = t.tire_compound.clone();
+                        self.cars.save_progress(client.car_id, &client.status());
                         self.broadcast_except_with(client, |c| {
                             c.send_packet(TestServer::ChangeTireCompound(client.into()))
                         })
                     }
                     TestClient::DamageUpdate(d) => {
                         client.update_damage(d);
+                        self.cars.save_progress(client.car_id, &client.status());
 
                         self.broadcast_except_with(client, |c| {
                             c.send_packet(TestServer::DamageUpdate(client.into()))
                         })
                     }
-                    TestClient::SectorSplit(_) => todo!(),
-                    TestClient::NextSessionVote(_) => todo!(),
-                    TestClient::RestartSessionVote(_) => todo!(),
-                    TestClient::KickVote(_) => todo!(),
-                    TestClient::Event(_) => todo!(),
+                    TestClient::SectorSplit(split) => {
+                        log::debug!("{} sector split {:?}", client.car_id, split);
+                        self.emit(ServerEvent::SectorSplit {
+                            car_id: client.car_id,
+                            sector: split.unknown,
+                            time: split.unknown2,
+                            cuts: split.unknown3,
+                        });
+                        self.broadcast_except_with(client, |c| {
+                            c.send_packet(TestServer::SectorSplit(SectorSplit {
+                                car_id: client.car_id as u8,
+                                unknown2: split.unknown,
+                                unknown3: split.unknown2,
+                                unknown4: split.unknown3,
+                            }))
+                        })
+                    }
+                    TestClient::NextSessionVote(v) => {
+                        votes.push((VoteKind::NextSession, client.car_id, v.unknown != 0))
+                    }
+                    TestClient::RestartSessionVote(v) => {
+                        votes.push((VoteKind::RestartSession, client.car_id, v.unknown != 0))
+                    }
+                    TestClient::KickVote(v) => votes.push((
+                        VoteKind::Kick(v.car_id.into()),
+                        client.car_id,
+                        v.unknown2 != 0,
+                    )),
+                    TestClient::Event(e) => {
+                        for event in e.events {
+                            let (event_type, other_car) = match event.event_type {
+                                CollisionType::CarCollision => {
+                                    client.status_mut().contacts += 1;
+                                    (0xa, event.other_car.map(usize::from))
+                                }
+                                CollisionType::WorldCollision => (0xb, None),
+                                CollisionType::UnknownCollision => continue,
+                            };
+                            self.emit(ServerEvent::Collision {
+                                car_id: client.car_id,
+                                other_car,
+                                impact_speed: event.impact_speed,
+                            });
+                            self.plugin
+                                .send(UdpPlugin::ClientEventPlugin(ClientEventPlugin {
+                                    event_type,
+                                    car_id: client.car_id as u8,
+                                    other_car: event.other_car,
+                                    impact_speed: event.impact_speed,
+                                    world_pos: event.world_pos,
+                                    real_pos: event.real_pos,
+                                }));
+                        }
+                    }
                     _ => {}
                 }
             }
         }
+        for (kind, voter, yes) in votes {
+            self.cast_vote(kind, voter, yes);
+        }
+        for (car_id, message) in chats {
+            match message.starts_with('/') {
+                true => admin::handle_chat_command(self, car_id, &message),
+                false => chat::handle_chat(self, car_id, &message),
+            }
+        }
     }
 
     pub fn handle_udp_messages(&mut self) {
-        for message in self.udp_packets.try_iter() {
-            //log::debug!("UDP:{:?}", message.packet);
-            match message.packet {
-                TestClient::CarUpdate(u) => {
-                    if let Some(client) = self.clients.get_from_ip(message.addr.ip()) {
-                        if !client.has_sent_first_update.get() {
-                            let bops: Bops = (&self.clients).into();
-                            let updates: Vec<PositionUpdate> =
-                                self.clients.iter().map(Into::into).collect();
-                            client.send_udp_packet(TestServer::MegaPacket(MegaPacket {
-                                timestamp: self.timestamp(),
-                                ping: client.status().ping as u16,
-                                position_updates: updates,
-                            }));
-
-                            client.send_packet(TestServer::WelcomeMessage(WelcomeMessage {
-                                unknown: 0,
-                                welcome_msg: self.config.server.name.clone().into(),
-                            }));
-
-                            client.send_packet(TestServer::Weather(
-                                self.options.read().unwrap().current_weather().into(),
-                            ));
-
-                            for i in self.clients.iter() {
-                                if i.car_id != client.car_id {
-                                    client.send_packet(TestServer::ChangeTireCompound(i.into()));
-                                }
-                                client.send_packet(TestServer::MandatoryPit(i.into()));
-                                client.send_packet(TestServer::P2PCount(i.into()));
-                            }
-                            client.send_packet(TestServer::Bops(bops));
-                            /*client.send_packet(TestServer::LapCompleted(LapCompleted {
-                                car_id: (),
-                                unknown1: (),
-                                unknown2: (),
-                                session_bests: (),
-                                grip_level: (),
-                            }));*/
-
-                            client.has_sent_first_update.set(true);
+        for message in self.udp_packets.clone().try_iter() {
+            self.handle_udp_message(message);
+        }
+    }
+
+    pub fn handle_udp_message(&mut self, message: UdpClientMessage) {
+        //log::debug!("UDP:{:?}", message.packet);
+        let is_association = matches!(
+            message.packet,
+            TestClient::UpdateUpdAddress(_) | TestClient::LobbyCheckMessage(_)
+        );
+        if !is_association && self.clients.get_from_udp(message.addr).is_none() {
+            log::trace!("Rejected udp from unknown endpoint {}", message.addr);
+            return;
+        }
+        let addr = message.addr;
+        match message.packet {
+            TestClient::CarUpdate(u) => {
+                if let Some(client) = self.clients.get_from_udp(message.addr) {
+                    if !client.has_sent_first_update.get() {
+                        let bops: Bops = (&self.clients).into();
+                        let updates: Vec<PositionUpdate> =
+                            self.clients.iter().map(Into::into).collect();
+                        client.send_udp_packet(TestServer::MegaPacket(MegaPacket {
+                            timestamp: self.timestamp(),
+                            ping: client.status().ping as u16,
+                            position_updates: updates,
+                        }));
+
+                        client.send_packet(TestServer::WelcomeMessage(WelcomeMessage {
+                            unknown: 0,
+                            welcome_msg: self
+                                .options
+                                .read()
+                                .unwrap()
+                                .welcome_message
+                                .clone()
+                                .into(),
+                        }));
+
+                        for line in chat::motd(&self.config) {
+                            client.send_chat(line);
                         }
 
-                        client.update_car(u);
-                    }
-                }
-                TestClient::UpdateUpdAddress(m) => {
-                    if let Some(client) = self.clients.get_from_ip(message.addr.ip()) {
-                        if let Some(car) = self.cars.lock().unwrap().get(client.car_id) {
-                            if car.session_id != m.car_id as usize {
-                                log::debug!("{} tried update wrong car", car.session_id);
+                        client.send_packet(TestServer::Weather(
+                            self.options.read().unwrap().current_weather().into(),
+                        ));
+
+                        for i in self.clients.iter() {
+                            if i.car_id != client.car_id {
+                                client.send_packet(TestServer::ChangeTireCompound(i.into()));
                             }
-                            client.set_udp(message.addr);
-                            client
-                                .send_udp_packet(TestServer::UpdateUpdAddress(UpdateUpdAddressS {}))
+                            client.send_packet(TestServer::MandatoryPit(i.into()));
+                            client.send_packet(TestServer::P2PCount(i.into()));
                         }
+                        client.send_packet(TestServer::Bops(bops));
+                        /*client.send_packet(TestServer::LapCompleted(LapCompleted {
+                            car_id: (),
+                            unknown1: (),
+                            unknown2: (),
+                            session_bests: (),
+                            grip_level: (),
+                        }));*/
+
+                        client.has_sent_first_update.set(true);
                     }
+
+                    client.update_car(u);
                 }
-                TestClient::LobbyCheckMessage(_) => {
-                    let _ = self.udp_packets_to_send.try_send(UdpServerMessage {
-                        addr: message.addr,
-                        packet: TestServer::LobbyCheckMessage(LobbyCheckMessage {
-                            http_port: self.config.server.http_port as u16,
-                        }),
-                    });
-                }
-                TestClient::Pong(p) => {
-                    if let Some(client) = self.clients.get_from_ip(message.addr.ip()) {
-                        let mut status = client.status_mut();
-                        status.ping = self.start_time.elapsed().as_millis() as u32 - p.ping;
-                        status.time_offset = status.ping / 2 + p.time_offset;
-                        status.last_pong_time = Instant::now();
+            }
+            TestClient::UpdateUpdAddress(m) => {
+                if let Some(client) = self.clients.get_from_udp(message.addr) {
+                    if client.car_id != m.car_id as usize {
+                        log::debug!("{} tried update wrong car {}", client.car_id, m.car_id);
+                        return;
                     }
+                    client.send_udp_packet(TestServer::UpdateUpdAddress(UpdateUpdAddressS {}));
+                } else if let Some(client) = self
+                    .clients
+                    .get_from_car_id(m.car_id as usize)
+                    .filter(|c| c.ip == addr.ip())
+                {
+                    log::debug!("{} registered udp address {}", client.car_id, message.addr);
+                    client.set_udp(message.addr);
+                    client.send_udp_packet(TestServer::UpdateUpdAddress(UpdateUpdAddressS {}));
+                } else {
+                    log::debug!(
+                        "{} tried to register udp for unknown car {}",
+                        message.addr,
+                        m.car_id
+                    );
                 }
-                TestClient::SessionRequest(r) => {
-                    let session_type = self
-                        .options
-                        .read()
-                        .unwrap()
-                        .sessions
-                        .get_current_session()
-                        .session_type
-                        .clone(); // :DD
-                    if r.session_type != session_type {
-                        if let Some(client) = self.clients.get_from_ip(message.addr.ip()) {
-                            client.send_session_update(self.options.clone());
-                        }
+            }
+            TestClient::LobbyCheckMessage(_) => {
+                let result = self.udp_packets_to_send.try_send(UdpServerMessage {
+                    addr: message.addr,
+                    packet: TestServer::LobbyCheckMessage(LobbyCheckMessage {
+                        http_port: self.config.server.http_port as u16,
+                    }),
+                });
+                self.metrics.track_send("udp", result);
+            }
+            TestClient::Pong(p) => {
+                if let Some(client) = self.clients.get_from_udp(message.addr) {
+                    let mut status = client.status_mut();
+                    status.ping = self.start_time.elapsed().as_millis() as u32 - p.ping;
+                    status.time_offset = status.ping / 2 + p.time_offset;
+                    status.last_pong_time = Instant::now();
+                }
+            }
+            TestClient::SessionRequest(r) => {
+                let session_type = self
+                    .options
+                    .read()
+                    .unwrap()
+                    .sessions
+                    .get_current_session()
+                    .session_type
+                    .clone(); // :DD
+                if r.session_type != session_type {
+                    if let Some(client) = self.clients.get_from_udp(message.addr) {
+                        client.send_session_update(self.options.clone());
                     }
                 }
-                _ => log::debug!("{} sent unknown udp message", message.addr.ip()),
             }
+            _ => log::debug!("{} sent unknown udp message", message.addr),
         }
     }
 
-    pub fn send_pings_and_updates(&self) {
-        let updates: Vec<PositionUpdate> = self.clients.iter().map(Into::into).collect();
+    pub fn send_pings_and_updates(&mut self) {
+        self.tick = self.tick.wrapping_add(1);
+        let updates: Vec<(PositionUpdate, TrackPosition)> = self
+            .clients
+            .iter()
+            .map(|c| (c.into(), TrackPosition::from(&*c.status())))
+            .collect();
         for client in self.clients.iter() {
             if !client.has_sent_first_update.get() {
                 continue;
@@ -332,11 +782,30 @@ impl Server {
                 }))
             }
 
-            client.send_udp_packet(TestServer::MegaPacket(MegaPacket {
-                timestamp: self.timestamp(),
-                ping: client.status().ping as u16,
-                position_updates: updates.clone(),
-            }));
+            let receiver = TrackPosition::from(&*client.status());
+            let visible: Vec<PositionUpdate> = updates
+                .iter()
+                .filter(|(update, position)| {
+                    let near = interest::is_near(&self.config.updates, &receiver, position);
+                    interest::should_send(
+                        &self.config.updates,
+                        self.tick,
+                        update.car_id.into(),
+                        near,
+                    )
+                })
+                .map(|(update, _position)| update.clone())
+                .collect();
+
+            for position_updates in
+                interest::split_updates(visible, self.config.updates.max_packet_size)
+            {
+                client.send_udp_packet(TestServer::MegaPacket(MegaPacket {
+                    timestamp: self.timestamp(),
+                    ping: client.status().ping as u16,
+                    position_updates,
+                }));
+            }
 
             if Instant::now().duration_since(client.status().last_pong_time)
                 > Duration::from_secs(10)
@@ -354,3 +823,10 @@ impl Server {
         self.start_time.elapsed().as_millis() as u32
     }
 }
+
+pub fn register(systems: &mut SystemExecutor<Server>) {
+    systems.add_system("updates", |server| {
+        server.send_pings_and_updates();
+        Ok(())
+    });
+}
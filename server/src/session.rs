@@ -1,14 +1,23 @@
+use std::collections::HashSet;
 use std::convert::TryInto;
 use std::time::{Duration, Instant};
 
 use crate::config::Session as CfgSession;
+use crate::server::Server;
+use crate::system::{SysResult, SystemExecutor};
 use protocol::packets::server::SessionType;
 
 use protocol::packets::server::SessionU as SessionPacket;
-#[derive(Clone, Debug)]
+use serde::{Deserialize, Serialize};
+
+/// When drivers can join a session, `IS_OPEN` in server_cfg.ini.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
 pub enum SessionOpenType {
     Closed,
+    #[default]
     Open,
+    /// Only before the session starts, while waiting for it.
     WaitOnly,
 }
 
@@ -18,8 +27,23 @@ pub struct Session {
     pub session_type: SessionType,
     pub end: Duration,
     pub laps: u16,
-    //  wait: Duration,
-    //    open_type: SessionOpenType,
+    pub wait: Duration,
+    pub open_type: SessionOpenType,
+    pub weather: Option<usize>,
+    pub grip: Option<f32>,
+}
+
+/// How far the current race is from its end.
+#[derive(Clone, Debug, Default)]
+struct RaceProgress {
+    /// Laps completed by the car in the lead.
+    leader_laps: u32,
+    /// Laps the leader has to complete in a timed race, known once the time is up.
+    target_laps: Option<u32>,
+    /// Cars that have crossed the finish line.
+    finished: HashSet<usize>,
+    /// When the leader crossed the finish line.
+    finished_at: Option<Instant>,
 }
 
 #[derive(Clone, Debug)]
@@ -27,20 +51,31 @@ pub struct Sessions {
     sessions: Vec<Session>,
     start: Instant,
     current: usize,
-    laps: usize,
+    race: RaceProgress,
 }
 
 impl Sessions {
+    /// The first race in the list, if the event has one.
+    pub fn race(&self) -> Option<&Session> {
+        self.sessions
+            .iter()
+            .find(|session| session.session_type == SessionType::Race)
+    }
+
     pub fn get_types(&self) -> Vec<u8> {
         self.sessions
             .iter()
             .map(|f| f.session_type.clone() as u8)
             .collect()
     }
+    /// Laps for lap races and seconds for everything else.
     pub fn get_durations(&self) -> Vec<i64> {
         self.sessions
             .iter()
-            .map(|f| f.end.as_secs() as i64)
+            .map(|f| match f.is_lap_race() {
+                true => f.laps as i64,
+                false => f.end.as_secs() as i64,
+            })
             .collect()
     }
 
@@ -50,37 +85,134 @@ impl Sessions {
     pub fn get_current(&self) -> usize {
         self.current
     }
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
 
-    pub fn is_over(&self) -> bool {
-        self.start.elapsed() >= self.get_current_session().end
-            || self.laps > self.get_current_session().laps.into()
+    /// Races are over `race_over_time` after the leader finished, once every car
+    /// in `connected` has or once nobody is connected, other sessions when their
+    /// time is up.
+    pub fn is_over(&self, connected: &[usize], race_over_time: Duration) -> bool {
+        let session = self.get_current_session();
+        if session.session_type != SessionType::Race {
+            return self.elapsed() >= session.end;
+        }
+        match self.race.finished_at {
+            Some(finished_at) => {
+                finished_at.elapsed() >= race_over_time
+                    || connected.iter().all(|car| self.race.finished.contains(car))
+            }
+            // nobody is left to finish the race
+            None => connected.is_empty() && self.has_started(),
+        }
+    }
+
+    /// Counts a lap of `car_id`, which has now completed `laps`, and returns whether
+    /// the car has finished the race.
+    ///
+    /// The leader finishes after the session's laps, or in a timed race on the first
+    /// crossing after the time is up, one lap later with `extra_lap`. Everyone else
+    /// finishes on their next crossing after that.
+    pub fn complete_lap(&mut self, car_id: usize, laps: u32, extra_lap: bool) -> bool {
+        let session = self.get_current_session();
+        if session.session_type != SessionType::Race {
+            return false;
+        }
+        let lap_race = session.is_lap_race();
+        let session_laps = session.laps.into();
+        let time_up = self.elapsed() >= session.end;
+
+        let race = &mut self.race;
+        if race.finished_at.is_some() {
+            race.finished.insert(car_id);
+            return true;
+        }
+        if laps <= race.leader_laps {
+            return false;
+        }
+        race.leader_laps = laps;
+        if !lap_race && time_up && race.target_laps.is_none() {
+            race.target_laps = Some(laps + u32::from(extra_lap));
+        }
+        let target = match lap_race {
+            true => Some(session_laps),
+            false => race.target_laps,
+        };
+        if target.is_some_and(|target| laps >= target) {
+            race.finished_at = Some(Instant::now());
+            race.finished.insert(car_id);
+            return true;
+        }
+        false
     }
     pub fn left_time(&self) -> Duration {
         self.get_current_session()
             .end
-            .checked_sub(self.start.elapsed())
+            .checked_sub(self.elapsed())
             .unwrap_or(Duration::default())
     }
 
+    /// Starts the current session once its wait time is over.
     pub fn start(&mut self) {
-        self.start = Instant::now();
+        self.start = Instant::now() + self.get_current_session().wait;
+        self.race = RaceProgress::default();
     }
     pub fn get_start(&self) -> Instant {
         self.start
     }
-    pub fn add_lap(&mut self) {
-        self.laps = self.laps + 1;
+    pub fn has_started(&self) -> bool {
+        Instant::now() >= self.start
+    }
+    /// Time since the session started, zero while waiting for it.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().saturating_duration_since(self.start)
     }
-    pub fn laps(&self) -> usize {
-        self.laps
+    /// Milliseconds since the session started, negative while waiting for it.
+    pub fn elapsed_ms(&self) -> i64 {
+        let now = Instant::now();
+        match now.checked_duration_since(self.start) {
+            Some(elapsed) => elapsed.as_millis() as i64,
+            None => -(self.start.duration_since(now).as_millis() as i64),
+        }
+    }
+
+    /// Whether drivers can join the current session right now.
+    pub fn is_joinable(&self) -> bool {
+        match self.get_current_session().open_type {
+            SessionOpenType::Closed => false,
+            SessionOpenType::Open => true,
+            SessionOpenType::WaitOnly => !self.has_started(),
+        }
+    }
+
+    /// Replaces the sessions after the current one, which keeps running.
+    pub fn replace_upcoming(&mut self, sessions: &Vec<CfgSession>) {
+        let upcoming = Sessions::from(sessions).sessions;
+        self.sessions.truncate(self.current + 1);
+        self.sessions
+            .extend(upcoming.into_iter().skip(self.current + 1));
     }
 
     pub fn next_session(&mut self) {
-        self.current = self.get_current() + 1 % self.sessions.len();
+        self.current = (self.get_current() + 1) % self.sessions.len();
         self.start();
     }
 }
 
+impl Session {
+    /// Races with laps end after them, races without are timed.
+    pub fn is_lap_race(&self) -> bool {
+        self.session_type == SessionType::Race && self.laps > 0
+    }
+
+    pub fn is_timed_race(&self) -> bool {
+        self.session_type == SessionType::Race && self.laps == 0
+    }
+}
+
 impl From<CfgSession> for Session {
     fn from(s: CfgSession) -> Self {
         Self {
@@ -88,8 +220,10 @@ impl From<CfgSession> for Session {
             session_type: s.session_type.into(),
             end: Duration::new(s.time.into(), 0),
             laps: s.laps,
-            // wait: s.wait,
-            // open_type: s.open_type,
+            wait: s.wait_time,
+            open_type: s.is_open,
+            weather: s.weather,
+            grip: s.grip,
         }
     }
 }
@@ -102,12 +236,16 @@ impl From<&Vec<CfgSession>> for Sessions {
             sessions.push(i.clone().into())
         }
 
-        Self {
+        let mut sessions = Self {
             sessions,
             start: Instant::now(),
             current: 0,
-            laps: 0,
+            race: RaceProgress::default(),
+        };
+        if !sessions.is_empty() {
+            sessions.start();
         }
+        sessions
     }
 }
 
@@ -124,3 +262,109 @@ impl From<Sessions> for Vec<SessionPacket> {
         ret
     }
 }
+
+pub fn register(systems: &mut SystemExecutor<Server>) {
+    systems.add_system("sessions", advance_sessions);
+}
+
+fn advance_sessions(server: &mut Server) -> SysResult {
+    let connected: Vec<usize> = server.clients.iter().map(|c| c.car_id).collect();
+    let is_over = server
+        .options
+        .read()
+        .unwrap()
+        .sessions
+        .is_over(&connected, server.config.sessions.race_over_time);
+    if is_over {
+        server.next_session();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn wait_only_sessions_close_once_started() {
+        let mut config = Config::default().sessions.sessions;
+        config[0].is_open = SessionOpenType::WaitOnly;
+        config[0].wait_time = Duration::from_secs(60);
+        let mut sessions = Sessions::from(&config);
+        assert!(!sessions.has_started());
+        assert!(sessions.is_joinable());
+        assert!(sessions.elapsed_ms() < 0);
+        assert_eq!(
+            sessions.left_time(),
+            Duration::new(config[0].time.into(), 0)
+        );
+
+        sessions.start = Instant::now();
+        assert!(sessions.has_started());
+        assert!(!sessions.is_joinable());
+    }
+
+    fn race(laps: u16) -> Sessions {
+        let mut config = Config::default().sessions.sessions;
+        config[0].session_type = 3;
+        config[0].laps = laps;
+        config[0].time = 60;
+        Sessions::from(&config)
+    }
+
+    #[test]
+    fn lap_race_ends_after_everyone_crossed_the_line() {
+        let mut sessions = race(2);
+        let over_time = Duration::from_secs(60);
+        assert!(!sessions.complete_lap(0, 1, false));
+        assert!(!sessions.complete_lap(1, 1, false));
+        assert!(sessions.complete_lap(0, 2, false));
+        assert!(!sessions.is_over(&[0, 1], over_time));
+        // a lapped car finishes on its next crossing as well
+        assert!(sessions.complete_lap(1, 2, false));
+        assert!(sessions.is_over(&[0, 1], over_time));
+    }
+
+    #[test]
+    fn timed_race_ends_a_lap_after_the_time_is_up_with_extra_lap() {
+        let mut sessions = race(0);
+        let over_time = Duration::from_secs(60);
+        assert!(!sessions.complete_lap(0, 1, true));
+        assert!(!sessions.is_over(&[0], over_time));
+
+        sessions.start -= Duration::from_secs(61);
+        assert!(!sessions.complete_lap(0, 2, true));
+        assert!(sessions.complete_lap(0, 3, true));
+        assert!(sessions.is_over(&[0], over_time));
+    }
+
+    #[test]
+    fn finds_the_race_while_practice_runs() {
+        let mut config = Config::default().sessions.sessions;
+        config[0].session_type = 1;
+        config.push(config[0].clone());
+        config[1].session_type = 3;
+        config[1].laps = 0;
+        let sessions = Sessions::from(&config);
+        assert_eq!(sessions.get_current(), 0);
+        assert!(sessions.race().is_some_and(|race| race.is_timed_race()));
+    }
+
+    #[test]
+    fn empty_race_ends_without_a_time_limit() {
+        let mut config = Config::default().sessions.sessions;
+        config[0].session_type = 3;
+        config[0].laps = 5;
+        config[0].time = 0;
+        config[0].wait_time = Duration::from_secs(60);
+        let mut sessions = Sessions::from(&config);
+        let over_time = Duration::from_secs(60);
+        // still waiting for drivers to join
+        assert!(!sessions.is_over(&[], over_time));
+
+        sessions.start = Instant::now();
+        assert!(!sessions.is_over(&[0], over_time));
+        assert!(sessions.is_over(&[], over_time));
+    }
+}
@@ -1,38 +1,154 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+
 pub type SysResult<T = ()> = anyhow::Result<T>;
 
 type SystemFn<Input> = Box<dyn FnMut(&mut Input) -> SysResult>;
+type RecoverFn<Input> = Box<dyn FnMut(&mut Input)>;
 
-struct SystemExecutor<Input> {
+/// Runs systems in the order they were added, once per tick.
+///
+/// An error or panic in one system is logged and does not stop the
+/// systems after it from running. A panic can leave the input broken,
+/// e.g. with a poisoned lock, see `SystemExecutor::on_panic` for fixing it up.
+pub struct SystemExecutor<Input> {
     systems: Vec<System<Input>>,
+    recover: Option<RecoverFn<Input>>,
 }
+
 struct System<Input> {
+    name: String,
     function: SystemFn<Input>,
+    stats: SystemStats,
+}
+
+/// Timings of a single system.
+#[derive(Debug, Default, Clone)]
+pub struct SystemStats {
+    pub runs: u64,
+    pub errors: u64,
+    pub last: Duration,
+    pub max: Duration,
 }
 
 impl<Input> System<Input> {
-    fn from_fn<F: FnMut(&mut Input) -> SysResult + 'static>(f: F) -> Self {
+    fn from_fn<F: FnMut(&mut Input) -> SysResult + 'static>(name: String, f: F) -> Self {
         Self {
+            name,
             function: Box::new(f),
+            stats: SystemStats::default(),
+        }
+    }
+}
+
+impl<Input> Default for SystemExecutor<Input> {
+    fn default() -> Self {
+        Self {
+            systems: Vec::new(),
+            recover: None,
         }
     }
 }
 
 impl<Input> SystemExecutor<Input> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a system that runs after every system added before it.
     pub fn add_system(
         &mut self,
+        name: impl Into<String>,
         system: impl FnMut(&mut Input) -> SysResult + 'static,
     ) -> &mut Self {
-        let system = System::from_fn(system);
+        let system = System::from_fn(name.into(), system);
         self.systems.push(system);
         self
     }
 
+    /// Sets what runs after a system panics, to put the input back in a state
+    /// the systems after it can use.
+    pub fn on_panic(&mut self, recover: impl FnMut(&mut Input) + 'static) -> &mut Self {
+        self.recover = Some(Box::new(recover));
+        self
+    }
+
     pub fn run(&mut self, input: &mut Input) {
-        for (i, system) in self.systems.iter_mut().enumerate() {
-            let result = (system.function)(input);
-            if let Err(e) = result {
-                log::error!("System {} returned an error; this is a bug", e);
+        for system in self.systems.iter_mut() {
+            let start = Instant::now();
+            let result = panic::catch_unwind(AssertUnwindSafe(|| (system.function)(input)));
+            let elapsed = start.elapsed();
+
+            system.stats.runs += 1;
+            system.stats.last = elapsed;
+            system.stats.max = system.stats.max.max(elapsed);
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    system.stats.errors += 1;
+                    log::error!("System {} returned an error: {:?}", system.name, e);
+                }
+                Err(_) => {
+                    system.stats.errors += 1;
+                    log::error!("System {} panicked; this is a bug", system.name);
+                    if let Some(recover) = &mut self.recover {
+                        recover(input);
+                    }
+                }
             }
         }
     }
+
+    /// Returns the name and timings of every system in run order.
+    pub fn stats(&self) -> impl Iterator<Item = (&str, &SystemStats)> + '_ {
+        self.systems
+            .iter()
+            .map(|system| (system.name.as_str(), &system.stats))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn failing_system_does_not_stop_others() {
+        let mut systems = SystemExecutor::<Vec<&str>>::new();
+        systems
+            .add_system("error", |_| anyhow::bail!("error"))
+            .add_system("panic", |_| panic!("panic"))
+            .add_system("push", |input| {
+                input.push("ran");
+                Ok(())
+            });
+
+        let mut input = Vec::new();
+        systems.run(&mut input);
+
+        assert_eq!(input, vec!["ran"]);
+        let errors: Vec<u64> = systems.stats().map(|(_, stats)| stats.errors).collect();
+        assert_eq!(errors, vec![1, 1, 0]);
+    }
+
+    #[test]
+    fn recovers_from_a_panic_holding_a_lock() {
+        let mut systems = SystemExecutor::<Arc<Mutex<Vec<&str>>>>::new();
+        systems
+            .add_system("panic", |input| {
+                let _locked = input.lock().unwrap();
+                panic!("panic")
+            })
+            .add_system("push", |input| {
+                input.lock().unwrap().push("ran");
+                Ok(())
+            })
+            .on_panic(|input| input.clear_poison());
+
+        let mut input = Arc::new(Mutex::new(Vec::new()));
+        systems.run(&mut input);
+        systems.run(&mut input);
+
+        assert_eq!(*input.lock().unwrap(), vec!["ran", "ran"]);
+    }
 }
@@ -1,39 +1,85 @@
 use std::time::Duration;
 use std::time::Instant;
 
+use tokio::time::{interval, Interval, MissedTickBehavior};
+
+/// How often overran ticks are summarized in the log.
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Tick timings, `overruns` counts ticks that took longer than the tick duration.
+#[derive(Debug, Default, Clone)]
+pub struct TickStats {
+    pub ticks: u64,
+    pub overruns: u64,
+    pub last: Duration,
+    pub max: Duration,
+}
+
 pub struct TickLoop {
-    function: Box<dyn FnMut() -> bool>,
+    interval: Interval,
     tick_duration: Duration,
+    stats: TickStats,
+    last_report: Instant,
+    worst_since_report: Duration,
+    overruns_at_last_report: u64,
+    ticks_at_last_report: u64,
 }
 
 impl TickLoop {
-    /// Creates a `TickLoop`. The given `function` is called
-    /// each tick. Returning `true` from `function` causes the
-    /// tick loop to exit.
-    pub fn new(tick_rate: u32, function: impl FnMut() -> bool + 'static) -> Self {
+    /// Creates a `TickLoop` that ticks `tick_rate` times a second.
+    /// Ticks missed because the server fell behind are skipped instead of bursting.
+    pub fn new(tick_rate: u32) -> Self {
         let tick_millis: u32 = 1000 / tick_rate;
         let tick_duration: Duration = Duration::from_millis(tick_millis as u64);
+        let mut interval = interval(tick_duration);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
         Self {
-            function: Box::new(function),
+            interval,
             tick_duration,
+            stats: TickStats::default(),
+            last_report: Instant::now(),
+            worst_since_report: Duration::default(),
+            overruns_at_last_report: 0,
+            ticks_at_last_report: 0,
         }
     }
 
-    /// Runs the tick loop until the callback returns `true`.
-    pub fn run(mut self) {
-        loop {
-            let start = Instant::now();
-            let should_exit = (self.function)();
-            if should_exit {
-                return;
-            }
+    /// Waits until the next tick is due.
+    pub async fn tick(&mut self) {
+        self.interval.tick().await;
+    }
+
+    /// Records how long the work of a tick took.
+    pub fn record(&mut self, elapsed: Duration) {
+        self.stats.ticks += 1;
+        self.stats.last = elapsed;
+        self.stats.max = self.stats.max.max(elapsed);
+        self.worst_since_report = self.worst_since_report.max(elapsed);
+        if elapsed > self.tick_duration {
+            self.stats.overruns += 1;
+        }
 
-            let elapsed = start.elapsed();
-            if elapsed > self.tick_duration {
-                log::warn!("Tick took too long ({:?})", elapsed);
-            } else {
-                std::thread::sleep(self.tick_duration - elapsed);
+        if self.last_report.elapsed() >= REPORT_INTERVAL {
+            let overruns = self.stats.overruns - self.overruns_at_last_report;
+            let ticks = self.stats.ticks - self.ticks_at_last_report;
+            if overruns > 0 {
+                log::warn!(
+                    "{} of {} ticks took longer than {:?} in the last {:?} (worst {:?})",
+                    overruns,
+                    ticks,
+                    self.tick_duration,
+                    REPORT_INTERVAL,
+                    self.worst_since_report
+                );
             }
+            self.last_report = Instant::now();
+            self.worst_since_report = Duration::default();
+            self.overruns_at_last_report = self.stats.overruns;
+            self.ticks_at_last_report = self.stats.ticks;
         }
     }
+
+    pub fn stats(&self) -> &TickStats {
+        &self.stats
+    }
 }
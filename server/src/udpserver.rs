@@ -1,4 +1,5 @@
 use crate::config::Config;
+use crate::metrics::{Direction, Metrics, Transport};
 use crate::option::ServerOptions;
 
 use crate::Cars;
@@ -12,6 +13,7 @@ use log::debug;
 use protocol::io::Writeable;
 
 use core::panic;
+use flume::TrySendError;
 use protocol::packets::client::TestClient;
 use protocol::packets::server::TestServer;
 use protocol::Codec;
@@ -19,15 +21,16 @@ use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use tokio::net::UdpSocket;
+
+const MAX_DATAGRAM_SIZE: usize = 2048;
+
 pub struct UdpServer {
     /*     config: Arc<Config>,
     options: Arc<RwLock<ServerOptions>>,
     cars: Arc<Cars>,*/
-    received_packets_tx: Sender<UdpClientMessage>,
     received_packets_rx: Receiver<UdpClientMessage>,
     packets_to_send_tx: Sender<UdpServerMessage>,
-    packets_to_send_rx: Receiver<UdpServerMessage>,
-    socket: UdpSocket,
+    local_addr: SocketAddr,
 }
 
 pub struct UdpClientMessage {
@@ -40,28 +43,31 @@ pub struct UdpServerMessage {
 }
 
 impl UdpServer {
-    pub async fn bind(config: Arc<Config>) -> Result<Self> {
-        let (received_packets_tx, received_packets_rx) = flume::bounded(32);
+    pub async fn bind(config: Arc<Config>, metrics: Arc<Metrics>) -> Result<Self> {
+        let (received_packets_tx, received_packets_rx) = flume::bounded(1024);
         let (packets_to_send_tx, packets_to_send_rx) = flume::unbounded();
 
         let address = format!("{}:{}", config.server.address, config.server.udp_port);
         let socket = UdpSocket::bind(address)
             .await
             .context("failed to bind to udp port - maybe a server is already running?")?;
+        let local_addr = socket.local_addr()?;
+        let socket = Arc::new(socket);
 
-        let udpserver = UdpServer {
+        tokio::spawn(UdpServer::receive(
+            Arc::clone(&socket),
             received_packets_tx,
+            Arc::clone(&metrics),
+        ));
+        tokio::spawn(UdpServer::send(socket, packets_to_send_rx, metrics));
+
+        let udpserver = UdpServer {
             received_packets_rx,
-            packets_to_send_rx,
             packets_to_send_tx,
-            socket,
+            local_addr,
         };
 
-        log::info!(
-            "Server is listening udp on {}:{}",
-            config.server.address,
-            config.server.udp_port
-        );
+        log::info!("Server is listening udp on {}", local_addr);
         Ok(udpserver)
     }
     /*
@@ -75,30 +81,62 @@ impl UdpServer {
     }*/
 
     //UDP packets dont have len before packet
-    pub fn send_udp(&self) {
-        for i in self.packets_to_send_rx.try_iter() {
-            let mut buffer = Vec::new();
+    /// Sends packets as soon as the server queues them, until every sender is dropped.
+    async fn send(
+        socket: Arc<UdpSocket>,
+        packets_to_send: Receiver<UdpServerMessage>,
+        metrics: Arc<Metrics>,
+    ) {
+        let mut buffer = Vec::new();
+        while let Ok(i) = packets_to_send.recv_async().await {
+            buffer.clear();
             if i.packet.write(&mut buffer).is_ok() {
-                log::debug!("sent: {:?}", i.packet);
-                let _ = self.socket.try_send_to(&buffer, i.addr);
+                log::trace!("sent: {:?}", i.packet);
+                metrics.packet(Transport::Udp, Direction::Out, i.packet.name());
+                let _ = socket.send_to(&buffer, i.addr).await;
             }
         }
     }
 
-    pub fn listen(&self) {
-        let mut buf = vec![0; 512];
+    /// Drains the socket until the server drops `received_packets`.
+    async fn receive(
+        socket: Arc<UdpSocket>,
+        received_packets: Sender<UdpClientMessage>,
+        metrics: Arc<Metrics>,
+    ) {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
         let mut codec = Codec::new();
-        if let Ok((len, addr)) = self.socket.try_recv_from(&mut buf) {
-            if let Ok(Some(packet)) = codec.decode::<TestClient>(&mut buf[..len].to_vec()) {
-                log::trace!("{:?}", packet);
-                let _ = self
-                    .received_packets_tx
-                    .try_send(UdpClientMessage { addr, packet });
-            } else {
-                log::error!("Failed to decode:{:?}", buf);
+        loop {
+            let (len, addr) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    // ICMP port unreachable from a client that went away shows up here
+                    log::trace!("udp receive failed: {}", e);
+                    continue;
+                }
+            };
+            let packet = match codec.decode::<TestClient>(&mut buf[..len].to_vec()) {
+                Ok(Some(packet)) => packet,
+                _ => {
+                    metrics.decode_error(Transport::Udp);
+                    log::debug!("{} failed to decode: {:?}", addr, &buf[..len]);
+                    continue;
+                }
+            };
+            log::trace!("{:?}", packet);
+            metrics.packet(Transport::Udp, Direction::In, packet.name());
+
+            match received_packets.try_send(UdpClientMessage { addr, packet }) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => metrics.dropped("udp_received"),
+                Err(TrySendError::Disconnected(_)) => return,
             }
         }
     }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
     pub fn received_packets(&self) -> Receiver<UdpClientMessage> {
         self.received_packets_rx.clone()
     }
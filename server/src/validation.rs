@@ -0,0 +1,448 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use anyhow::bail;
+
+use crate::config::Config;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    /// The server cannot run with this config.
+    Error,
+    /// The server runs, but probably not as intended.
+    Warning,
+}
+
+/// A problem with the value at `path`, written as a TOML path like `weathers[0].base_road`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Issue {
+    pub severity: Severity,
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", severity, self.path, self.message)
+    }
+}
+
+/// Every problem found in a config.
+#[derive(Debug, Default, Clone)]
+pub struct ValidationReport {
+    pub issues: Vec<Issue>,
+}
+
+impl ValidationReport {
+    fn error(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.push(Severity::Error, path.into(), message.into());
+    }
+
+    fn warning(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.push(Severity::Warning, path.into(), message.into());
+    }
+
+    fn push(&mut self, severity: Severity, path: String, message: String) {
+        self.issues.push(Issue {
+            severity,
+            path,
+            message,
+        });
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &Issue> + '_ {
+        self.issues
+            .iter()
+            .filter(|issue| issue.severity == Severity::Error)
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &Issue> + '_ {
+        self.issues
+            .iter()
+            .filter(|issue| issue.severity == Severity::Warning)
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.errors().next().is_some()
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for issue in self.issues.iter() {
+            writeln!(f, "{}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    /// Logs the warnings of `report` and fails with every error in it.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let report = self.report();
+        for warning in report.warnings() {
+            log::warn!("{}", warning);
+        }
+        if report.has_errors() {
+            let errors: Vec<String> = report.errors().map(|e| e.to_string()).collect();
+            bail!("invalid config:\n{}", errors.join("\n"));
+        }
+        Ok(())
+    }
+
+    /// Checks the whole config, including the content folders if `server.content_dir` is set.
+    pub fn report(&self) -> ValidationReport {
+        let mut report = ValidationReport::default();
+        self.check_server(&mut report);
+        self.check_weathers(&mut report);
+        self.check_sessions(&mut report);
+        self.check_game(&mut report);
+        self.check_cars(&mut report);
+        self.check_chat(&mut report);
+        self.check_content(&mut report);
+        report
+    }
+
+    fn check_server(&self, report: &mut ValidationReport) {
+        let server = &self.server;
+        if server.client_send_interval_hz == 0 {
+            report.error("server.client_send_interval_hz", "must be at least 1");
+        }
+        // a TCP and a UDP socket can share a port, the Kunos defaults use 9600 for both
+        let mut ports = vec![
+            ("server.tcp_port", "tcp", server.tcp_port),
+            ("server.udp_port", "udp", server.udp_port),
+            ("server.http_port", "tcp", server.http_port),
+        ];
+        if let Some(plugin) = &self.plugin {
+            ports.push(("plugin.local_port", "udp", plugin.local_port));
+        }
+        for (i, (path, protocol, port)) in ports.iter().enumerate() {
+            // 0 picks a free port
+            if *port == 0 {
+                continue;
+            }
+            let used_by = ports[..i]
+                .iter()
+                .find(|(_, other_protocol, other)| other_protocol == protocol && other == port);
+            if let Some((used_by, ..)) = used_by {
+                report.error(
+                    *path,
+                    format!("port {} is already used by {}", port, used_by),
+                );
+            }
+        }
+        if self.lobby.register_to_lobby {
+            if !self.lobby.url.starts_with("http://") {
+                report.error("lobby.url", "only http:// lobbies are supported");
+            }
+            if self.lobby.ping_interval.is_zero() {
+                report.error("lobby.ping_interval", "must be at least 1");
+            }
+        }
+        if self.updates.far_update_interval == 0 {
+            report.error("updates.far_update_interval", "must be at least 1");
+        }
+    }
+
+    fn check_weathers(&self, report: &mut ValidationReport) {
+        if self.weathers.is_empty() {
+            report.error("weathers", "at least one weather is needed");
+        }
+        for (i, w) in self.weathers.iter().enumerate() {
+            let path = format!("weathers[{}]", i);
+            if w.base_road + w.variation_road >= 75.0 {
+                report.error(
+                    format!("{}.base_road", path),
+                    "road temperature cannot be over 75°C",
+                );
+            }
+            if w.base_ambient + w.variation_ambient >= 45.0 {
+                report.error(
+                    format!("{}.base_ambient", path),
+                    "ambient temperature cannot be over 45°C",
+                );
+            }
+            if w.wind.base_speed_min > w.wind.base_speed_max {
+                report.error(
+                    format!("{}.wind.base_speed_min", path),
+                    "cannot be bigger than base_speed_max",
+                );
+            }
+            if w.wind.base_speed_min > 40 || w.wind.base_speed_max > 40 {
+                report.error(format!("{}.wind", path), "wind speed cannot be over 40 m/s");
+            }
+            // the weather picks values between base - variation and base + variation
+            if w.variation_ambient < 0.0 {
+                report.error(format!("{}.variation_ambient", path), "cannot be negative");
+            }
+            if w.variation_road < 0.0 {
+                report.error(format!("{}.variation_road", path), "cannot be negative");
+            }
+            if w.wind.variation_direction < 0 {
+                report.error(
+                    format!("{}.wind.variation_direction", path),
+                    "cannot be negative",
+                );
+            }
+        }
+    }
+
+    fn check_sessions(&self, report: &mut ValidationReport) {
+        if self.sessions.is_empty() {
+            report.error("sessions.sessions", "at least one session is needed");
+        }
+        for (i, s) in self.sessions.iter().enumerate() {
+            let path = format!("sessions.sessions[{}]", i);
+            if s.session_type > 3 {
+                report.error(
+                    format!("{}.session_type", path),
+                    format!(
+                        "unknown session type {}, expected 0 (booking), 1 (practice), 2 (qualify) or 3 (race)",
+                        s.session_type
+                    ),
+                );
+            } else if s.session_type == 0 && i != 0 {
+                report.warning(
+                    format!("{}.session_type", path),
+                    "booking only works as the first session",
+                );
+            }
+            let lap_race = s.session_type == 3 && s.laps > 0;
+            if s.time == 0 && !lap_race {
+                report.error(format!("{}.time", path), "session time cannot be 0");
+            }
+            if let Some(weather) = s.weather {
+                if weather >= self.weathers.len() {
+                    report.error(
+                        format!("{}.weather", path),
+                        format!("there are only {} weathers", self.weathers.len()),
+                    );
+                }
+            }
+            if let Some(grip) = s.grip {
+                if !(0.0..=1.0).contains(&grip) {
+                    report.error(format!("{}.grip", path), "must be between 0.0 and 1.0");
+                }
+            }
+            if s.wait_time.as_secs() > u16::MAX.into() {
+                report.error(format!("{}.wait_time", path), "is too long");
+            }
+        }
+        if self.sessions.result_screen_time.as_millis() < 10000 {
+            report.error("sessions.result_screen_time", "cannot be lower than 10000");
+        }
+        if self.sessions.race_over_time.as_millis() < 30000 {
+            report.error("sessions.race_over_time", "cannot be lower than 30000");
+        }
+    }
+
+    fn check_game(&self, report: &mut ValidationReport) {
+        let game = &self.game;
+        if game.pit_window_enabled() && game.pit_window_end <= game.pit_window_start {
+            report.error(
+                "game.pit_window_end",
+                "cannot be smaller than pit_window_start",
+            );
+        }
+        if game.voting_quorum > 100 {
+            report.error("game.voting_quorum", "is a percentage, 0 to 100");
+        }
+        if game.kick_quorum > 100 {
+            report.error("game.kick_quorum", "is a percentage, 0 to 100");
+        }
+    }
+
+    fn check_cars(&self, report: &mut ValidationReport) {
+        if self.cars.is_empty() {
+            report.error("cars", "at least one car is needed");
+        }
+        let max_clients = self.server.max_clients as usize;
+        if max_clients != self.cars.len() {
+            report.warning(
+                "server.max_clients",
+                format!(
+                    "is {} but there are {} cars, at most {} drivers can join",
+                    max_clients,
+                    self.cars.len(),
+                    max_clients.min(self.cars.len())
+                ),
+            );
+        }
+        let mut guids = HashSet::new();
+        for (i, car) in self.cars.iter().enumerate() {
+            if let Some(guid) = &car.guid {
+                if !guids.insert(guid) {
+                    report.warning(
+                        format!("cars[{}].guid", i),
+                        format!("{} already has a car reserved", guid),
+                    );
+                }
+            }
+        }
+    }
+
+    fn check_chat(&self, report: &mut ValidationReport) {
+        if let Some(motd) = &self.chat.motd_file {
+            if !motd.is_file() {
+                report.warning(
+                    "chat.motd_file",
+                    format!("{} does not exist", motd.display()),
+                );
+            }
+        }
+        if self.chat.max_messages > 0 && self.chat.rate_window.is_zero() {
+            report.error(
+                "chat.rate_window",
+                "must be at least 1 with max_messages set",
+            );
+        }
+        for (i, announcement) in self.chat.announcements.iter().enumerate() {
+            if announcement.message.trim().is_empty() {
+                report.error(format!("chat.announcements[{}].message", i), "is empty");
+            }
+        }
+    }
+
+    fn check_content(&self, report: &mut ValidationReport) {
+        let content = match &self.server.content_dir {
+            Some(content) => content,
+            None => return,
+        };
+        if !content.is_dir() {
+            report.error(
+                "server.content_dir",
+                format!("{} does not exist", content.display()),
+            );
+            return;
+        }
+        let track = content.join("tracks").join(&self.track);
+        if !track.is_dir() {
+            report.error(
+                "track",
+                format!("{} not found in {}", self.track, track.display()),
+            );
+        }
+        for (i, car) in self.cars.iter().enumerate() {
+            let path = content.join("cars").join(&car.model);
+            if !path.is_dir() {
+                report.error(
+                    format!("cars[{}].model", i),
+                    format!("{} not found in {}", car.model, path.display()),
+                );
+                continue;
+            }
+            let skin = path.join("skins").join(&car.skin);
+            if !car.skin.is_empty() && !skin.is_dir() {
+                report.error(
+                    format!("cars[{}].skin", i),
+                    format!("{} not found in {}", car.skin, skin.display()),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_every_problem_with_its_path() {
+        let mut config = Config::default();
+        config.weathers[0].base_ambient = 50.0;
+        config.sessions.sessions[0].session_type = 7;
+        config.sessions.sessions[0].time = 0;
+        config.server.http_port = config.server.tcp_port;
+        config.server.max_clients = 20;
+
+        let report = config.report();
+        let errors: Vec<&str> = report.errors().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                "server.http_port",
+                "weathers[0].base_ambient",
+                "sessions.sessions[0].session_type",
+                "sessions.sessions[0].time",
+            ]
+        );
+        let warnings: Vec<&str> = report.warnings().map(|e| e.path.as_str()).collect();
+        assert_eq!(warnings, vec!["server.max_clients"]);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn weathers_that_pass_the_check_can_start() {
+        let mut config = Config::default();
+        let weather = &mut config.weathers[0];
+        weather.variation_ambient = 0.0;
+        weather.variation_road = 0.0;
+        weather.wind.base_speed_min = 0;
+        weather.wind.base_speed_max = 0;
+        weather.wind.variation_direction = 0;
+        assert!(!config.report().has_errors());
+        let options = crate::option::ServerOptions::new(std::sync::Arc::new(config));
+        options.write().unwrap().update_weather();
+
+        let mut config = Config::default();
+        config.weathers[0].variation_road = -1.0;
+        config.weathers[0].wind.variation_direction = -10;
+        let report = config.report();
+        let errors: Vec<&str> = report.errors().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                "weathers[0].variation_road",
+                "weathers[0].wind.variation_direction"
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_ports_used_twice() {
+        let mut config = Config::default();
+        config.server.tcp_port = 9600;
+        config.server.udp_port = 9600;
+        config.server.http_port = 9600;
+        config.plugin = Some(crate::config::PluginOptions {
+            address: "127.0.0.1:11000".parse().unwrap(),
+            local_port: 9600,
+        });
+
+        let errors: Vec<String> = config.report().errors().map(|e| e.to_string()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                "error: server.http_port: port 9600 is already used by server.tcp_port",
+                "error: plugin.local_port: port 9600 is already used by server.udp_port",
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_models_and_skins_in_the_content_dir() {
+        let content = std::env::temp_dir().join("assetto-validation-content");
+        let model = content.join("cars").join("ks_mazda_mx5_cup");
+        std::fs::create_dir_all(model.join("skins").join("00_official")).unwrap();
+        std::fs::create_dir_all(content.join("tracks").join("ks_vallelunga")).unwrap();
+
+        let mut config = Config::default();
+        config.server.content_dir = Some(content);
+        config.track = "ks_vallelunga".into();
+        for car in config.cars.iter_mut() {
+            car.model = "ks_mazda_mx5_cup".into();
+        }
+        config.cars[0].skin = "00_official".into();
+        config.cars[1].skin = "missing".into();
+        config.cars[2].model = "missing".into();
+
+        let report = config.report();
+        let errors: Vec<&str> = report.errors().map(|e| e.path.as_str()).collect();
+        assert_eq!(errors, vec!["cars[1].skin", "cars[2].model"]);
+    }
+}
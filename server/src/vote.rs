@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use protocol::packets::server::{
+    KickReason, KickVote, NextSessionVote, RestartSessionVote, TestServer,
+};
+
+use crate::event::ServerEvent;
+use crate::server::Server;
+use crate::system::{SysResult, SystemExecutor};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VoteKind {
+    NextSession,
+    RestartSession,
+    Kick(usize),
+}
+
+#[derive(Debug)]
+pub struct Vote {
+    pub kind: VoteKind,
+    pub deadline: Instant,
+    votes: HashMap<usize, bool>,
+}
+
+impl Vote {
+    pub fn yes_votes(&self) -> usize {
+        self.votes.values().filter(|yes| **yes).count()
+    }
+
+    fn has_passed(&self, connected: usize, quorum: u8) -> bool {
+        connected > 0 && self.yes_votes() * 100 >= connected * quorum as usize
+    }
+}
+
+/// The vote in progress, only one vote can run at a time.
+#[derive(Debug, Default)]
+pub struct Votes {
+    current: Option<Vote>,
+}
+
+impl Votes {
+    /// Casts a vote of `voter`, starting a new vote of `kind` if none is running.
+    /// Returns `None` if a vote of a different kind is already running.
+    pub fn cast(
+        &mut self,
+        kind: VoteKind,
+        voter: usize,
+        yes: bool,
+        duration: Duration,
+    ) -> Option<&Vote> {
+        let vote = self.current.get_or_insert_with(|| Vote {
+            kind,
+            deadline: Instant::now() + duration,
+            votes: HashMap::new(),
+        });
+        if vote.kind != kind {
+            return None;
+        }
+        vote.votes.insert(voter, yes);
+        Some(vote)
+    }
+
+    /// Ends the running vote once it has passed or timed out,
+    /// returning its kind and whether it passed.
+    pub fn take_result(&mut self, connected: usize, quorum: u8) -> Option<(VoteKind, bool)> {
+        let vote = self.current.as_ref()?;
+        let passed = vote.has_passed(connected, quorum);
+        if !passed && Instant::now() < vote.deadline {
+            return None;
+        }
+        let kind = vote.kind;
+        self.current = None;
+        Some((kind, passed))
+    }
+
+    pub fn current(&self) -> Option<&Vote> {
+        self.current.as_ref()
+    }
+}
+
+pub fn register(systems: &mut SystemExecutor<Server>) {
+    systems.add_system("votes", resolve_votes);
+}
+
+impl Server {
+    /// Records the vote of `voter` and lets every client know the standing.
+    pub fn cast_vote(&mut self, kind: VoteKind, voter: usize, yes: bool) {
+        let duration = self.config.game.vote_duration;
+        let quorum = self.quorum(kind);
+        if self.votes.cast(kind, voter, yes, duration).is_none() {
+            log::debug!("{} voted while another vote is running", voter);
+            return;
+        }
+
+        self.emit(ServerEvent::VoteCast {
+            kind,
+            car_id: voter,
+            yes,
+        });
+
+        let vote = self.votes.current().expect("vote was just cast");
+        let yes_votes = vote.yes_votes() as u8;
+        let dead_line = vote
+            .deadline
+            .saturating_duration_since(Instant::now())
+            .as_millis() as u32;
+        let packet = match kind {
+            VoteKind::NextSession => TestServer::NextSessionVote(NextSessionVote {
+                useless: 0,
+                unknown: quorum,
+                unknown1: yes_votes,
+                dead_line,
+                last_voter: voter as u8,
+                last_vote: yes,
+            }),
+            VoteKind::RestartSession => TestServer::RestartSessionVote(RestartSessionVote {
+                useless: 0,
+                unknown: quorum,
+                unknown1: yes_votes,
+                dead_line,
+                last_voter: voter as u8,
+                last_vote: yes,
+            }),
+            VoteKind::Kick(car_id) => TestServer::KickVote(KickVote {
+                car_id: car_id as u8,
+                unknown: quorum,
+                unknown1: yes_votes,
+                dead_line,
+                last_voter: voter as u8,
+                last_vote: yes,
+            }),
+        };
+        self.broadcast_with(|c| c.send_packet(packet.clone()));
+    }
+
+    fn quorum(&self, kind: VoteKind) -> u8 {
+        match kind {
+            VoteKind::Kick(_) => self.config.game.kick_quorum,
+            _ => self.config.game.voting_quorum,
+        }
+    }
+}
+
+fn resolve_votes(server: &mut Server) -> SysResult {
+    let kind = match server.votes.current() {
+        Some(vote) => vote.kind,
+        None => return Ok(()),
+    };
+    let connected = server.clients.iter().count();
+    let quorum = server.quorum(kind);
+    if let Some((kind, passed)) = server.votes.take_result(connected, quorum) {
+        log::info!("Vote {:?} passed: {}", kind, passed);
+        server.emit(ServerEvent::VoteResolved { kind, passed });
+        if passed {
+            match kind {
+                VoteKind::NextSession => server.next_session(),
+                VoteKind::RestartSession => server.restart_session(),
+                VoteKind::Kick(car_id) => server.kick(car_id, KickReason::Kick),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vote_passes_with_quorum() {
+        let mut votes = Votes::default();
+        let duration = Duration::from_secs(60);
+        votes
+            .cast(VoteKind::NextSession, 0, true, duration)
+            .unwrap();
+        assert!(votes
+            .cast(VoteKind::RestartSession, 1, true, duration)
+            .is_none());
+        assert_eq!(votes.take_result(3, 75), None);
+
+        votes
+            .cast(VoteKind::NextSession, 1, true, duration)
+            .unwrap();
+        votes
+            .cast(VoteKind::NextSession, 2, true, duration)
+            .unwrap();
+        assert_eq!(
+            votes.take_result(3, 75),
+            Some((VoteKind::NextSession, true))
+        );
+        assert!(votes.current().is_none());
+    }
+
+    #[test]
+    fn vote_fails_after_deadline() {
+        let mut votes = Votes::default();
+        votes
+            .cast(VoteKind::Kick(1), 0, true, Duration::from_secs(0))
+            .unwrap();
+        assert_eq!(votes.take_result(3, 85), Some((VoteKind::Kick(1), false)));
+    }
+}
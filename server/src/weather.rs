@@ -1,9 +1,16 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use protocol::packets::server::SunAngle as SunAnglePacket;
+use protocol::packets::server::TestServer;
 use protocol::packets::server::Weather as WeatherPacket;
 use rand::Rng;
 
+use crate::server::Server;
+use crate::system::{SysResult, SystemExecutor};
+
+/// How often clients get the current sun angle.
+const SUN_ANGLE_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Debug, Clone)]
 pub struct Weather {
     pub graphics: String,
@@ -81,6 +88,11 @@ impl Temperature {
         }
     }
     pub fn update(&mut self) {
+        // `gen_range` cannot pick from an empty range
+        if self.variation <= 0.0 {
+            self.temp = self.base_temp;
+            return;
+        }
         let mut rng = rand::thread_rng();
         self.temp = rng.gen_range(
             self.base_temp - self.variation,
@@ -128,7 +140,8 @@ impl Wind {
         variation_direction: i32,
     ) -> Self {
         let mut rng = rand::thread_rng();
-        let speed = rng.gen_range(speed_min, speed_max);
+        // the ranges include their end, so equal bounds pick that value
+        let speed = rng.gen_range(speed_min, speed_max + 1);
         Self {
             speed,
             direction: base_direction,
@@ -140,16 +153,50 @@ impl Wind {
     }
     pub fn update(&mut self) {
         let mut rng = rand::thread_rng();
-        self.speed = rng.gen_range(self.speed_min, self.speed_max);
+        self.speed = rng.gen_range(self.speed_min, self.speed_max + 1);
 
         let direction = rng.gen_range(
             self.base_direction - self.variation_direction,
-            self.base_direction + self.variation_direction,
+            self.base_direction + self.variation_direction + 1,
         );
 
         self.direction = direction % 360;
     }
 }
+pub fn register(systems: &mut SystemExecutor<Server>) {
+    let mut last_sun_angle = Instant::now();
+    let mut session = None;
+    systems.add_system("weather", move |server| {
+        update_weather(server, &mut last_sun_angle, &mut session)
+    });
+}
+
+/// Picks a new weather when the session changes and keeps the sun moving.
+fn update_weather(
+    server: &mut Server,
+    last_sun_angle: &mut Instant,
+    session: &mut Option<usize>,
+) -> SysResult {
+    let mut options = server.options.write().unwrap();
+    let current = options.sessions.get_current();
+    if *session != Some(current) {
+        if session.is_some() {
+            options.update_weather();
+            let packet = TestServer::Weather(options.current_weather().into());
+            server.broadcast_with(|c| c.send_packet(packet.clone()));
+        }
+        *session = Some(current);
+    }
+
+    if last_sun_angle.elapsed() >= SUN_ANGLE_INTERVAL {
+        options.sun_angle.sun_angle = options.sun_angle.calc();
+        let packet = TestServer::SunAngle(options.sun_angle.clone().into());
+        server.broadcast_with(|c| c.send_packet(packet.clone()));
+        *last_sun_angle = Instant::now();
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
 
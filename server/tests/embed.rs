@@ -0,0 +1,282 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+use std::time::Duration;
+
+use hyper::{body, Body, Client, Method, Request, StatusCode};
+use protocol::json::{Info, JSON};
+use protocol::packets::client::{JoinRequest, TestClient};
+use protocol::packets::common::PROTOCOL_VERSION;
+use protocol::Codec;
+use server::config::Config;
+use server::session::SessionOpenType;
+use server::ServerBuilder;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+fn test_config() -> Config {
+    let mut config = Config::default();
+    config.server.address = Ipv4Addr::LOCALHOST;
+    config.server.tcp_port = 0;
+    config.server.udp_port = 0;
+    config.server.http_port = 0;
+    config.server.results_dir = std::env::temp_dir().join("assetto-results");
+    config
+}
+
+async fn get(addr: SocketAddr, path: &str) -> StatusCode {
+    let uri = format!("http://{}{}", addr, path).parse().unwrap();
+    Client::new().get(uri).await.unwrap().status()
+}
+
+async fn get_json<T: serde::de::DeserializeOwned>(addr: SocketAddr, path: &str) -> T {
+    let uri = format!("http://{}{}", addr, path).parse().unwrap();
+    let response = Client::new().get(uri).await.unwrap();
+    let bytes = body::to_bytes(response.into_body()).await.unwrap();
+    serde_json::from_slice(&bytes).unwrap()
+}
+
+/// Joins as `guid` in a car of `model` and waits for the server's answer.
+async fn join(addr: SocketAddr, guid: &str, model: &str) -> TcpStream {
+    join_with_password(addr, guid, model, "").await
+}
+
+async fn join_with_password(
+    addr: SocketAddr,
+    guid: &str,
+    model: &str,
+    password: &str,
+) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let join = TestClient::JoinRequest(JoinRequest {
+        protocol_version: PROTOCOL_VERSION,
+        guid: guid.into(),
+        driver_name: guid.into(),
+        unknown: 0,
+        driver_country: "FIN".into(),
+        car_name: model.into(),
+        server_password: password.into(),
+    });
+    let mut bytes = Vec::new();
+    Codec::new().encode(&join, &mut bytes).unwrap();
+    stream.write_all(&bytes).await.unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await.unwrap();
+    stream
+}
+
+/// Polls `/JSON` until `done` is true for it, the server takes new players in on its own time.
+async fn wait_for_json(addr: SocketAddr, done: impl Fn(&JSON) -> bool) -> JSON {
+    for _ in 0..50 {
+        let json: JSON = get_json(addr, "/JSON").await;
+        if done(&json) {
+            return json;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("/JSON never got there");
+}
+
+#[tokio::test]
+async fn runs_several_servers_in_one_process() {
+    let first = ServerBuilder::new(test_config()).start().await.unwrap();
+    let second = ServerBuilder::new(test_config()).start().await.unwrap();
+    let (first_addr, second_addr) = (first.addresses(), second.addresses());
+    assert_ne!(first_addr.tcp, second_addr.tcp);
+
+    assert_eq!(get(first_addr.http, "/INFO").await, StatusCode::OK);
+    assert_eq!(get(second_addr.http, "/INFO").await, StatusCode::OK);
+
+    first.shutdown().await.unwrap();
+    assert!(TcpStream::connect(first_addr.tcp).await.is_err());
+    assert!(TcpStream::connect(second_addr.tcp).await.is_ok());
+    second.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn reports_bind_errors() {
+    let running = ServerBuilder::new(test_config()).start().await.unwrap();
+    let mut config = test_config();
+    config.server.tcp_port = running.addresses().tcp.port();
+    assert!(ServerBuilder::new(config).start().await.is_err());
+}
+
+#[tokio::test]
+async fn lobby_sees_booked_cars() {
+    let mut config = test_config();
+    config.cars[1].guid = Some("76561198000000000".into());
+    let server = ServerBuilder::new(config).start().await.unwrap();
+    let http = server.addresses().http;
+
+    let info: Info = get_json(http, "/INFO").await;
+    assert_eq!(info.country, vec!["na", "na"]);
+    assert!(info.pickup);
+    assert!(!info.l);
+
+    let json: JSON = get_json(http, "/JSON|76561198000000000").await;
+    let requested: Vec<bool> = json.cars.iter().map(|c| c.is_requested_guid).collect();
+    assert_eq!(requested, vec![false, true, false]);
+    let json: JSON = get_json(http, "/JSON").await;
+    assert!(json.cars.iter().all(|c| !c.is_requested_guid));
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn books_a_car_during_the_booking_session() {
+    let mut config = test_config();
+    config.sessions.sessions[0].session_type = 0;
+    config.game.password = Some("secret".into());
+    let server = ServerBuilder::new(config).start().await.unwrap();
+    let http = server.addresses().http;
+
+    let text = |path: &'static str| async move {
+        let uri = format!("http://{}{}", http, path).parse().unwrap();
+        let response = Client::new().get(uri).await.unwrap();
+        let bytes = body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    };
+    assert_eq!(text("/SUB|123|Driver").await, "INCORRECT PASSWORD");
+    assert_eq!(
+        text("/SUB|123|Driver|other_car|secret").await,
+        "ILLEGAL CAR"
+    );
+    assert_eq!(text("/SUB|123|Driver||secret").await, "OK");
+
+    let entries: JSON = get_json(http, "/ENTRY").await;
+    assert_eq!(entries.cars.len(), 1);
+    assert_eq!(entries.cars[0].driver_name, "Driver");
+
+    assert_eq!(text("/UNSUB|123").await, "OK");
+    assert_eq!(text("/UNSUB|123").await, "NOT BOOKED");
+    server.shutdown().await.unwrap();
+
+    let server = ServerBuilder::new(test_config()).start().await.unwrap();
+    let uri = format!("http://{}/UNSUB|123", server.addresses().http)
+        .parse()
+        .unwrap();
+    let response = Client::new().get(uri).await.unwrap();
+    let bytes = body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(&bytes[..], b"CLOSED");
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn admin_api_needs_the_token() {
+    let mut config = test_config();
+    config.server.admin_token = Some("token".into());
+    let server = ServerBuilder::new(config).start().await.unwrap();
+    let http = server.addresses().http;
+
+    let call = |method: Method, path: &'static str, token: &'static str, body: &'static str| async move {
+        let request = Request::builder()
+            .method(method)
+            .uri(format!("http://{}{}", http, path))
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::from(body))
+            .unwrap();
+        let response = Client::new().request(request).await.unwrap();
+        let status = response.status();
+        let bytes = body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(bytes.to_vec()).unwrap())
+    };
+    assert_eq!(get(http, "/api/drivers").await, StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        call(Method::GET, "/api/drivers", "token", "").await,
+        (StatusCode::OK, "[]".to_string())
+    );
+    assert_eq!(
+        call(Method::GET, "/api/next_session", "token", "").await.0,
+        StatusCode::METHOD_NOT_ALLOWED
+    );
+    assert_eq!(
+        call(Method::POST, "/api/next_session", "token", "").await.0,
+        StatusCode::NO_CONTENT
+    );
+    assert_eq!(
+        call(Method::POST, "/api/kick", "token", r#"{"car_id":0}"#).await,
+        (
+            StatusCode::BAD_REQUEST,
+            r#"{"error":"no driver in car 0"}"#.to_string()
+        )
+    );
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn dashboard_logs_in_with_the_admin_password() {
+    let server = ServerBuilder::new(test_config()).start().await.unwrap();
+    assert_eq!(
+        get(server.addresses().http, "/admin").await,
+        StatusCode::NOT_FOUND
+    );
+    server.shutdown().await.unwrap();
+
+    let mut config = test_config();
+    config.game.admin_password = Some("director".into());
+    let server = ServerBuilder::new(config).start().await.unwrap();
+    let http = server.addresses().http;
+    assert_eq!(get(http, "/admin").await, StatusCode::OK);
+
+    let request = Request::builder()
+        .uri(format!("http://{}/api/standings", http))
+        .header("Authorization", "Bearer director")
+        .body(Body::empty())
+        .unwrap();
+    let response = Client::new().request(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    server.shutdown().await.unwrap();
+
+    let mut config = test_config();
+    config.server.admin_token = Some("token".into());
+    let server = ServerBuilder::new(config).start().await.unwrap();
+    assert_eq!(get(server.addresses().http, "/admin").await, StatusCode::OK);
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn rejoining_in_another_car_frees_the_old_one() {
+    let mut config = test_config();
+    config.cars[2].model = "ks_bmw_m3_e30".into();
+    let server = ServerBuilder::new(config).start().await.unwrap();
+    let addresses = server.addresses();
+    let drivers =
+        |json: &JSON| -> Vec<String> { json.cars.iter().map(|c| c.driver_name.clone()).collect() };
+
+    let _first = join(addresses.tcp, "1", "ks_mercedes_190_evo2").await;
+    wait_for_json(addresses.http, |json| drivers(json) == ["1", "", ""]).await;
+
+    let _second = join(addresses.tcp, "1", "ks_bmw_m3_e30").await;
+    let json = wait_for_json(addresses.http, |json| drivers(json) == ["", "", "1"]).await;
+    assert!(!json.cars[0].is_connected);
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn reconnecting_driver_joins_a_closed_session() {
+    let mut config = test_config();
+    config.sessions.sessions[0].is_open = SessionOpenType::Closed;
+    config.game.admin_password = Some("director".into());
+    let server = ServerBuilder::new(config).start().await.unwrap();
+    let addresses = server.addresses();
+    let model = "ks_mercedes_190_evo2";
+    let connected =
+        |json: &JSON| -> Vec<bool> { json.cars.iter().map(|c| c.is_connected).collect() };
+
+    // admins can join a closed session, a lost connection keeps their car reserved
+    let first = join_with_password(addresses.tcp, "1", model, "director").await;
+    wait_for_json(addresses.http, |json| {
+        connected(json) == [true, false, false]
+    })
+    .await;
+    drop(first);
+    wait_for_json(addresses.http, |json| {
+        connected(json) == [false, false, false]
+    })
+    .await;
+
+    let _second = join(addresses.tcp, "1", model).await;
+    wait_for_json(addresses.http, |json| {
+        connected(json) == [true, false, false]
+    })
+    .await;
+    server.shutdown().await.unwrap();
+}